@@ -1,5 +1,6 @@
 use anyhow::{anyhow, Context};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use encoding_rs::Encoding;
 use std::path::PathBuf;
 
 /// コマンドラインオプション
@@ -35,6 +36,63 @@ pub struct Opt {
     /// run in daemon mode
     #[arg(short, long)]
     pub daemon: bool,
+    /// Disable extended attributes (enabled by default, uses getfattr/setfattr and perl on the remote)
+    #[arg(long)]
+    pub no_xattr: bool,
+    /// Use the remote inode numbers, stable across remounts (uses stat/find on the remote)
+    #[arg(long)]
+    pub stable_inode: bool,
+    /// Support re-exporting the mount over NFS (use with --stable-inode to survive remounts)
+    #[arg(long)]
+    pub export: bool,
+    /// Get nanosecond times, ctime, nlink and blocks with the remote stat command
+    #[arg(long)]
+    pub rich_stat: bool,
+    /// How to map remote uid/gid (default: show all files as owned by the local user)
+    #[arg(long, value_enum)]
+    pub idmap: Option<IdMap>,
+    /// uid mapping file for --idmap file (lines of "local user name:remote uid")
+    #[arg(long)]
+    pub uidfile: Option<PathBuf>,
+    /// gid mapping file for --idmap file (lines of "local group name:remote gid")
+    #[arg(long)]
+    pub gidfile: Option<PathBuf>,
+    /// Leave holes instead of writing all-zero blocks (uses fallocate on the remote inside files)
+    #[arg(long)]
+    pub sparse_write: bool,
+    /// How to handle file locks (remote takes fcntl locks via perl on the remote, flock stays local)
+    #[arg(long, value_enum, default_value_t = Locking::Local)]
+    pub locking: Locking,
+    /// Character set of the remote file names, e.g. sjis or euc-jp (converted to UTF-8)
+    #[arg(long, value_parser = charset)]
+    pub remote_charset: Option<&'static Encoding>,
+    /// Rewrite absolute symlinks pointing inside the mount, so they work on the mount
+    #[arg(long)]
+    pub transform_symlinks: bool,
+}
+
+/// uid/gidの対応付けの方法
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IdMap {
+    /// Show the remote ids as they are
+    None,
+    /// Map the connecting remote user to the local user
+    User,
+    /// Map with --uidfile/--gidfile
+    File,
+    /// Map by user and group names (uses getent on the remote)
+    Name,
+}
+
+/// ファイルロックの扱い
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Locking {
+    /// Lock only within this host (the kernel handles locks)
+    Local,
+    /// Also take fcntl locks on the remote file, so other hosts see them (flock stays local)
+    Remote,
+    /// Refuse locks with ENOLCK
+    None,
 }
 
 /// 指定されたディレクトリが存在し、中にファイルがないことを確認する。
@@ -54,6 +112,19 @@ fn exist_dir(s: &str) -> anyhow::Result<String> {
     }
 }
 
+/// 文字コード名から、文字コードを求める。
+/// '/'の位置がずれないよう、ASCII互換のもの(Shift_JIS, EUC-JP等)のみとする。
+fn charset(s: &str) -> anyhow::Result<&'static Encoding> {
+    let encoding =
+        Encoding::for_label(s.as_bytes()).ok_or_else(|| anyhow!("Unknown character set."))?;
+    if !encoding.is_ascii_compatible() {
+        return Err(anyhow!(
+            "Character sets not compatible with ASCII are not supported."
+        ));
+    }
+    Ok(encoding)
+}
+
 /// コマンドラインの接続先ホスト情報
 #[derive(Clone, Debug, PartialEq)]
 pub struct RemoteName {
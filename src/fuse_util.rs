@@ -5,9 +5,10 @@ use anyhow::{ensure, Context, Result};
 use ssh2::Session;
 use std::env::current_dir;
 use std::{
+    ffi::OsStr,
     io::Read,
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
-    str,
 };
 
 /// マウントポイントのフルパスを生成する
@@ -27,8 +28,9 @@ pub fn make_remote_path(opt: &Opt, session: &Session) -> Result<PathBuf> {
     const MSG_ERRORHOME: &str = "Fail to generate path name.";
     let mut path = match opt.remote.path {
         Some(ref p) => {
+            let p = encode_remote_path(opt, p)?;
             if p.is_absolute() {
-                p.clone()
+                p
             } else {
                 let mut h = get_home_on_remote(session).context(MSG_ERRORHOME)?;
                 h.push(p);
@@ -90,6 +92,22 @@ pub fn make_mount_option(cmd_opt: &Opt) -> Vec<fuser::MountOption> {
     options
 }
 
+/// コマンドラインで指定したリモートのパスを、リモートの文字コードに変換する。
+fn encode_remote_path(opt: &Opt, path: &Path) -> Result<PathBuf> {
+    let Some(encoding) = opt.remote_charset else {
+        return Ok(path.to_path_buf());
+    };
+    let path = path
+        .to_str()
+        .context("The remote path contains non-utf8 characters.")?;
+    let (bytes, _, unmappable) = encoding.encode(path);
+    ensure!(
+        !unmappable,
+        "The remote path cannot be represented in the remote character set."
+    );
+    Ok(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
 /// ssh接続先のカレントディレクトリを取得する
 fn get_home_on_remote(session: &Session) -> Result<PathBuf> {
     let mut channel = session
@@ -103,9 +121,8 @@ fn get_home_on_remote(session: &Session) -> Result<PathBuf> {
         .read_to_end(&mut buf)
         .context("Fail to get response for \"pwd\" command.")?;
     channel.close().context("Fail to close ssh channel.")?;
-    str::from_utf8(&buf)
-        .context("The pwd result contains non-utf8 characters.")?
-        .trim()
-        .parse::<PathBuf>()
-        .context("Fail to build path name.")
+    // パスは、UTF-8とは限らないので、バイト列のまま扱う。(末尾の改行のみ除く)
+    let path = buf.strip_suffix(b"\n").unwrap_or(&buf);
+    ensure!(!path.is_empty(), "The pwd result is empty.");
+    Ok(PathBuf::from(OsStr::from_bytes(path)))
 }
@@ -1,61 +1,403 @@
 mod bi_hash_map;
+mod charset;
+mod dir_handle;
 mod file_handle;
+mod id_map;
 mod inode;
+mod lock;
+mod remote_cmd;
+mod remote_ino;
+mod remote_stat;
+mod remote_user;
+mod sparse;
+mod statfs;
+mod symlink;
+mod xattr;
 
+use charset::Charset;
+use dir_handle::{Dhandles, DirStream, ReadDir};
 use file_handle::Fhandles;
-use inode::Inodes;
+use id_map::IdMapper;
+use inode::{Inodes, ROOT_INODE};
+use lock::Locks;
+use remote_ino::RemoteIno;
+use remote_stat::RemoteStat;
+use remote_user::RemoteUser;
+use statfs::StatFs;
 
+use crate::cmdline_opt::{Locking, Opt};
 use anyhow::Context;
 use fuser::{FileAttr, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
 use libc::ENOENT;
 use log::{debug, error, warn};
 use ssh2::{ErrorCode, OpenFlags, OpenType, Session, Sftp};
 use std::{
-    ffi::OsStr,
+    borrow::Cow,
+    ffi::{OsStr, OsString},
     io::{Read, Seek, SeekFrom, Write},
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 /// FUSE ファイルシステム実装
 pub struct Sshfs {
-    _session: Session,
+    session: Session,
     sftp: Sftp,
     inodes: Inodes,
     fhandls: Fhandles,
-    _top_path: PathBuf,
+    dhandls: Dhandles,
+    top_path: PathBuf,
+    remote_user: Option<RemoteUser>,
+    xattr: bool,
+    /// 取得できなかった拡張属性のキャッシュ
+    xattr_missing: xattr::Missing,
+    /// --stable-inode時、リモートのinode番号を使う。
+    remote_ino: Option<RemoteIno>,
+    /// --export時、NFSでの再エクスポートに対応する。
+    export: bool,
+    /// カーネルに返す世代番号(generation)
+    generation: u64,
+    /// --rich-stat時、リモートのstatで詳細な属性を取得する。(使えない場合はfalseに戻す)
+    rich_stat: bool,
+    /// リモートのuid/gidの、ローカル側への対応付け
+    idmap: IdMapper,
+    /// --sparse-write時、全て0の書き込みを、ファイル内では穴を開けて、ファイル末尾以降ではサイズの拡張で済ませる。
+    sparse_write: bool,
+    /// ファイルロックの扱い
+    locking: Locking,
+    /// --locking remote時の、ロックの管理(保留中の要求を再試行するスレッドと共有する)
+    locks: Arc<Mutex<Locks>>,
+    /// ファイル名の文字コード変換
+    charset: Charset,
+    /// statfsに、SFTPのfstatvfs@openssh.com拡張を使うか。(使えない場合はfalseにして、以後はdfを使う)
+    sftp_statvfs: bool,
+    /// --transform-symlinks時、マウント内を指す絶対パスのリンク先を変換する。
+    transform_symlinks: bool,
+    /// ローカルのマウントポイント(フルパス)
+    mount_point: PathBuf,
 }
 
 impl Sshfs {
-    pub fn new<P: AsRef<Path>>(session: Session, path: P) -> anyhow::Result<Self> {
-        let mut inodes = Inodes::new();
+    pub fn new<P: AsRef<Path>>(
+        session: Session,
+        path: P,
+        mount_point: &Path,
+        opt: &Opt,
+    ) -> anyhow::Result<Self> {
         let top_path: PathBuf = path.as_ref().into();
-        inodes.add(&top_path);
+        let remote_ino = if opt.stable_inode {
+            RemoteIno::fetch(&session, &top_path)
+                .inspect_err(|e| {
+                    warn!(
+                        "Failed to get remote inode number. inode numbers are not stable. -- {:?}",
+                        e
+                    )
+                })
+                .ok()
+        } else {
+            None
+        };
+        let mut inodes = match remote_ino {
+            // リモートのinode番号と重ならない範囲を、連番に使う。
+            Some(_) => Inodes::with_first_inode(&top_path, remote_ino::FALLBACK_INODE_BASE),
+            None => Inodes::new(&top_path),
+        };
+        // NFSのファイルハンドルは、inode番号と世代番号の組。
+        // inode番号がマウント毎に変わる場合、以前のマウントのハンドルが別のファイルを指さないよう、
+        // 世代番号をマウント毎に変えて、古いハンドルを無効(ESTALE)にする。
+        let generation = match (opt.export, remote_ino) {
+            (true, None) => {
+                warn!("NFS file handles do not survive remount without --stable-inode.");
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(1, |d| d.as_secs())
+            }
+            _ => 0,
+        };
+        inodes.set_keep_forgotten(opt.export);
         let sftp = session
             .sftp()
             .inspect_err(|_| {
                 error!("Failed to create sftp from session.");
             })
             .context("Failed to create sftp from session.(Sshfs::new)")?;
+        let remote_user = RemoteUser::fetch(&session)
+            .inspect_err(|e| {
+                warn!(
+                    "Failed to get remote user id. access() is not emulated. -- {:?}",
+                    e
+                )
+            })
+            .ok();
+        let idmap = IdMapper::from_opt(opt, &session, remote_user.as_ref())?;
+        let locks = Arc::new(Mutex::new(Locks::new()));
+        if opt.locking == Locking::Remote {
+            lock::spawn_poller(&locks, session.clone());
+        }
         debug!(
-            "[Sshfs::new] connect path: <{:?}>, inodes=<{:?}>",
-            &top_path, &inodes
+            "[Sshfs::new] connect path: <{:?}>, inodes=<{:?}>, remote user=<{:?}>",
+            &top_path, &inodes, &remote_user
         );
         Ok(Self {
-            _session: session,
+            session,
             sftp,
             inodes,
             fhandls: Fhandles::new(),
-            _top_path: top_path,
+            dhandls: Dhandles::new(),
+            top_path,
+            remote_user,
+            xattr: !opt.no_xattr,
+            xattr_missing: xattr::Missing::new(),
+            remote_ino,
+            export: opt.export,
+            generation,
+            rich_stat: opt.rich_stat,
+            idmap,
+            sparse_write: opt.sparse_write,
+            locking: opt.locking,
+            locks,
+            charset: Charset::new(opt.remote_charset),
+            sftp_statvfs: true,
+            transform_symlinks: opt.transform_symlinks,
+            mount_point: mount_point.to_path_buf(),
         })
     }
 
-    /// ssh2経由でファイルのステータスを取得する。
-    /// 副作用:取得に成功した場合、inodesにパスを登録する。
-    fn getattr_from_ssh2(&mut self, path: &Path, uid: u32, gid: u32) -> Result<FileAttr, Error> {
+    /// ssh2経由で、登録済みのinodeのファイルのステータスを取得する。
+    fn getattr_from_ssh2(
+        &mut self,
+        ino: u64,
+        path: &Path,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr, Error> {
+        let attr_ssh2 = self.sftp.lstat(path)?;
+        let (uid, gid) = self.idmap.local_ids(&attr_ssh2, uid, gid);
+        let mut attr = Self::make_attr(ino, &attr_ssh2, uid, gid)?;
+        self.apply_remote_stat(path, &mut attr);
+        Ok(attr)
+    }
+
+    /// --rich-stat時、リモートのstatで取得した詳細な属性を反映する。
+    /// 取得できなければ、SFTPの属性のままとする。statコマンドが使えなければ、以後は取得しない。
+    fn apply_remote_stat(&mut self, path: &Path, attr: &mut FileAttr) {
+        if !self.rich_stat {
+            return;
+        }
+        match RemoteStat::fetch(&self.session, path) {
+            Ok(stat) => stat.apply(attr),
+            Err(e) => self.check_remote_stat_error(path, e),
+        }
+    }
+
+    /// リモートのstatのエラーを確認し、statコマンドが使えなければ、--rich-statを無効にする。
+    fn check_remote_stat_error(&mut self, path: &Path, e: Error) {
+        if e.0 == libc::ENOSYS {
+            warn!("Remote stat command is not available. --rich-stat is disabled.");
+            self.rich_stat = false;
+        } else {
+            debug!("[remote_stat] 属性取得失敗 {:?} -- {:?}", path, e);
+        }
+    }
+
+    /// --rich-stat時、ディレクトリ内のエントリの詳細な属性を、まとめて取得しておく。
+    fn load_remote_stats(&mut self, ino: u64, dir: &mut DirStream) {
+        if !self.rich_stat || dir.has_remote_stats() {
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
+            return;
+        };
+        match RemoteStat::fetch_dir(&self.session, &path) {
+            Ok(stats) => dir.set_remote_stats(stats),
+            Err(e) => {
+                self.check_remote_stat_error(&path, e);
+                dir.set_remote_stats(Default::default());
+            }
+        }
+    }
+
+    /// ssh2経由でファイルのステータスを取得し、カーネルへエントリとして返すために、inodeを登録する。
+    /// pathは、親ディレクトリ(parent)内のnameのリモート側のパス。
+    /// 副作用:取得に成功した場合、inodesに(parent, name)を登録し、参照数(lookup count)を1増やす。
+    /// カーネルは、この参照数をforgetで返してくる。
+    fn lookup_from_ssh2(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        path: &Path,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr, Error> {
         let attr_ssh2 = self.sftp.lstat(path)?;
+        let (uid, gid) = self.idmap.local_ids(&attr_ssh2, uid, gid);
+        let mut attr = Self::make_attr(0, &attr_ssh2, uid, gid)?;
+        self.apply_remote_stat(path, &mut attr);
+        attr.ino = match self.remote_ino_of(parent, name, path) {
+            Some(ino) => self.inodes.lookup_with_ino(parent, name, ino),
+            None => self.inodes.lookup(parent, name),
+        };
+        Ok(attr)
+    }
+
+    /// --stable-inode時、未登録のエントリのinode番号を、リモートのinode番号から求める。
+    /// 登録済みのもの、および、取得に失敗したものはNone。(連番を使う)
+    fn remote_ino_of(&self, parent: u64, name: &OsStr, path: &Path) -> Option<u64> {
+        let remote_ino = self.remote_ino?;
+        if self.inodes.get_inode(parent, name).is_some() {
+            return None;
+        }
+        remote_ino
+            .ino_of(&self.session, path)
+            .inspect_err(|e| debug!("[remote_ino_of] inode番号取得失敗 {:?} -- {:?}", path, e))
+            .ok()
+    }
+
+    /// chownで指定されたローカルのuid/gidを、リモートのuid/gidに変換する。
+    /// SFTPではuidとgidを組で設定するので、指定のない方は現在の値とする。
+    /// 変更がなければNone。対応付けられないidが指定されたらEPERM。
+    fn remote_owner(
+        &self,
+        path: &Path,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        req_uid: u32,
+        req_gid: u32,
+    ) -> Result<Option<(u32, u32)>, Error> {
+        if uid.is_none() && gid.is_none() {
+            return Ok(None);
+        }
+        let current = self.sftp.lstat(path)?;
+        let (Some(cur_uid), Some(cur_gid)) = (current.uid, current.gid) else {
+            return Err(Error(libc::EPERM));
+        };
+        // 今見えている値と同じ指定は、変更しない。(対応付けできないモードでも可)
+        let (shown_uid, shown_gid) = self.idmap.local_ids(&current, req_uid, req_gid);
+        let new_uid = match uid {
+            None => cur_uid,
+            Some(u) if u == shown_uid => cur_uid,
+            Some(u) => self.idmap.remote_uid(u).ok_or(Error(libc::EPERM))?,
+        };
+        let new_gid = match gid {
+            None => cur_gid,
+            Some(g) if g == shown_gid => cur_gid,
+            Some(g) => self.idmap.remote_gid(g).ok_or(Error(libc::EPERM))?,
+        };
+        if (new_uid, new_gid) == (cur_uid, cur_gid) {
+            return Ok(None);
+        }
+        Ok(Some((new_uid, new_gid)))
+    }
+
+    /// SEEK_DATA/SEEK_HOLEの位置を、リモートのファイルの配置から求める。
+    /// リモートで調べられなければ、ファイル全体をデータとみなす。(末尾にのみ穴がある)
+    fn seek_data_hole(
+        &self,
+        path: &Path,
+        file: &mut ssh2::File,
+        offset: i64,
+        hole: bool,
+    ) -> Result<u64, Error> {
+        if offset < 0 {
+            return Err(Error(libc::ENXIO));
+        }
+        match sparse::seek(&self.session, path, offset as u64, hole) {
+            Err(Error(libc::ENOSYS | libc::EINVAL)) => {
+                debug!("[seek_data_hole] リモートで調べられない。ファイル全体をデータとみなす。");
+            }
+            r => return r,
+        }
+        let size = file.stat()?.size.unwrap_or(0);
+        match offset as u64 {
+            o if o >= size => Err(Error(libc::ENXIO)),
+            _ if hole => Ok(size),
+            o => Ok(o),
+        }
+    }
+
+    /// ディレクトリinoの、オフセットiのエントリ("." "..")のinode番号。
+    fn dot_ino(&self, ino: u64, i: i64) -> u64 {
+        match i {
+            0 => ino,
+            _ => self.inodes.get_parent(ino).unwrap_or(ino),
+        }
+    }
+
+    /// "." ".."のlookup。(NFSエクスポート時に、カーネルから要求される)
+    /// "."は、ファイルハンドルからinodeを引くために使われ、カーネルがforgetしたinodeも来る。
+    fn lookup_dot(
+        &mut self,
+        ino: u64,
+        name: &OsStr,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr, Error> {
+        if self.inodes.get_path(ino).is_none() && !self.resolve_inode(ino) {
+            return Err(Error(libc::ESTALE));
+        }
+        let target = if name == "." {
+            ino
+        } else {
+            self.inodes.get_parent(ino).ok_or(Error(libc::ESTALE))?
+        };
+        let path = self.inodes.get_path(target).ok_or(Error(libc::ESTALE))?;
+        let attr = self.getattr_from_ssh2(target, &path, uid, gid)?;
+        if !self.inodes.lookup_inode(target) {
+            return Err(Error(libc::ESTALE));
+        }
+        Ok(attr)
+    }
+
+    /// 未登録のinodeを、リモートのinode番号から検索して登録する。(--stable-inode時のみ)
+    /// 登録できればtrueを返す。
+    fn resolve_inode(&mut self, ino: u64) -> bool {
+        let Some(remote_ino) = self.remote_ino else {
+            return false;
+        };
+        match remote_ino.find_by_ino(&self.session, &self.top_path, ino) {
+            Ok(Some(components)) => {
+                let mut parent = ROOT_INODE;
+                for (name, i) in components {
+                    parent = self.inodes.add_with_ino(parent, &name, i);
+                }
+                parent == ino
+            }
+            Ok(None) => false,
+            Err(e) => {
+                warn!("[resolve_inode] inode={}の検索に失敗 -- {:?}", ino, e);
+                false
+            }
+        }
+    }
+
+    /// --stable-inode時、ディレクトリ内のエントリのinode番号を、まとめて取得しておく。
+    fn load_remote_inos(&self, ino: u64, dir: &mut DirStream) {
+        let Some(remote_ino) = self.remote_ino else {
+            return;
+        };
+        if dir.has_remote_inos() {
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
+            return;
+        };
+        let inos = remote_ino
+            .inos_in_dir(&self.session, &path)
+            .inspect_err(|e| warn!("[load_remote_inos] inode番号取得失敗 {:?} -- {:?}", path, e))
+            .unwrap_or_default();
+        dir.set_remote_inos(inos);
+    }
+
+    /// ssh2のファイルステータスから、FUSEのファイル属性を生成する。
+    fn make_attr(
+        ino: u64,
+        attr_ssh2: &ssh2::FileStat,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr, Error> {
         let kind = Self::conv_file_kind_ssh2fuser(&attr_ssh2.file_type())?;
-        let ino = self.inodes.add(path);
         Ok(FileAttr {
             ino,
             size: attr_ssh2.size.unwrap_or(0),
@@ -66,6 +408,7 @@ impl Sshfs {
             crtime: UNIX_EPOCH,
             kind,
             perm: attr_ssh2.perm.unwrap_or(0o666) as u16,
+            // SFTPv3の属性にリンク数はない。--rich-stat時は、リモートのstatの値で置き換える。
             nlink: 1,
             uid,
             gid,
@@ -88,37 +431,383 @@ impl Sshfs {
         }
     }
 
+    /// ディレクトリのエントリの名前を、ローカルの名前に変換する。
+    /// ローカルの名前にすると長すぎるものは、見せられないので、ログに残してNone。
+    fn local_name<'a>(&self, name: &'a OsStr) -> Option<Cow<'a, OsStr>> {
+        self.charset
+            .to_local(name)
+            .inspect_err(|_| warn!("[readdir] 名前が長すぎるので省略 {:?}", name))
+            .ok()
+    }
+
+    /// ディレクトリハンドルから、オフセットiのエントリを(名前, 属性)として取得する。
+    /// オフセット0,1は"." "..", 以降はディレクトリハンドルから読んだエントリ。
+    /// エントリが尽きた場合は、Noneを返す。
+    fn dir_entry<D: ReadDir>(
+        dir: &mut DirStream<D>,
+        i: i64,
+    ) -> Result<Option<(OsString, ssh2::FileStat)>, Error> {
+        let cur_file_attr = ssh2::FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(libc::S_IFDIR),
+            atime: None,
+            mtime: None,
+        }; // "." ".."の解決用。 attr ディレクトリであることのみを示す。
+        match i {
+            0 => Ok(Some((".".into(), cur_file_attr))),
+            1 => Ok(Some(("..".into(), cur_file_attr))),
+            _ => match dir.get((i - 2) as usize) {
+                Ok(e) => Ok(e.cloned()),
+                Err(e) => {
+                    warn!("[dir_entry]ssh2::readdir内でエラー発生-- {:?}", e);
+                    Err(Error::from(e))
+                }
+            },
+        }
+    }
+
+    /// open(2)のフラグを、ssh2のOpenFlagsに変換する。
+    fn conv_open_flags(flags: i32) -> OpenFlags {
+        let mut flags_ssh2 = OpenFlags::empty();
+        if flags & libc::O_WRONLY != 0 {
+            flags_ssh2.insert(OpenFlags::WRITE);
+        } else if flags & libc::O_RDWR != 0 {
+            flags_ssh2.insert(OpenFlags::READ);
+            flags_ssh2.insert(OpenFlags::WRITE);
+        } else {
+            flags_ssh2.insert(OpenFlags::READ);
+        }
+        if flags & libc::O_APPEND != 0 {
+            flags_ssh2.insert(OpenFlags::APPEND);
+        }
+        if flags & libc::O_CREAT != 0 {
+            flags_ssh2.insert(OpenFlags::CREATE);
+        }
+        if flags & libc::O_TRUNC != 0 {
+            flags_ssh2.insert(OpenFlags::TRUNCATE);
+        }
+        if flags & libc::O_EXCL != 0 {
+            flags_ssh2.insert(OpenFlags::EXCLUSIVE);
+        }
+        flags_ssh2
+    }
+
     fn conv_timeornow2systemtime(time: &fuser::TimeOrNow) -> SystemTime {
         match time {
             fuser::TimeOrNow::SpecificTime(t) => *t,
             fuser::TimeOrNow::Now => SystemTime::now(),
         }
     }
+
+    /// カーネルのlookup回数を減らし、inodeが破棄されたら、そのinodeのキャッシュも捨てる。
+    fn forget_inode(&mut self, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+        if self.inodes.get_path(ino).is_none() {
+            self.xattr_missing.clear(ino);
+        }
+    }
+
+    /// 削除したファイルのinodeを登録から外し、なくなったinodeのキャッシュも捨てる。
+    /// (--stable-inode時は、同じinode番号が別のファイルに使われることがあるため)
+    fn remove_inode(&mut self, parent: u64, name: &OsStr) {
+        if let Some(ino) = self.inodes.remove(parent, name) {
+            if self.inodes.get_path(ino).is_none() {
+                self.xattr_missing.clear(ino);
+            }
+        }
+    }
+
+    /// 取得したファイルシステムの統計情報を返信する。
+    fn reply_statfs(reply: fuser::ReplyStatfs, stat: &StatFs) {
+        reply.statfs(
+            stat.blocks,
+            stat.bfree,
+            stat.bavail,
+            stat.files,
+            stat.ffree,
+            stat.bsize,
+            stat.namelen,
+            stat.frsize,
+        );
+    }
+
+    /// 拡張属性の値または一覧を、要求されたサイズに応じて返信する。
+    fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+        if size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(data);
+        }
+    }
+
+    /// リモートの"ln"コマンドで、ハードリンクを作成する。
+    /// SFTPv3の属性にリンク数はないので、作成後のリンク数を"ls -ldn"で取得して返す。
+    /// (リンク数が取得できなかった場合はNone)
+    fn link_on_remote(&self, src: &Path, dst: &Path) -> Result<Option<u32>, Error> {
+        let out = remote_cmd::exec(&self.session, &Self::link_command(src, dst)?)?.into_result()?;
+        Ok(Self::parse_nlink(&out))
+    }
+
+    /// ハードリンクを作成し、作成したリンクを"ls -ldn"で表示するコマンド。
+    /// lsが失敗しても、リンクの作成は成功として扱う。
+    fn link_command(src: &Path, dst: &Path) -> Result<String, Error> {
+        let dst = remote_cmd::quote(dst)?;
+        Ok(format!(
+            "ln -- {} {dst} && {{ ls -ldn -- {dst} || true; }}",
+            remote_cmd::quote(src)?
+        ))
+    }
+
+    /// "ls -ld"の出力(2番目の列)から、リンク数を取り出す。
+    fn parse_nlink(ls_output: &[u8]) -> Option<u32> {
+        let line = ls_output.split(|b| *b == b'\n').next()?;
+        let nlink = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|f| !f.is_empty())
+            .nth(1)?;
+        std::str::from_utf8(nlink).ok()?.parse().ok()
+    }
+
+    /// リモートのコマンドで、通常ファイル以外のノード(FIFO、ソケット、デバイス)を作成する。
+    fn mknod_on_remote(&self, path: &Path, mode: u32, perm: u32, rdev: u32) -> Result<(), Error> {
+        let command = Self::mknod_command(path, mode, perm, rdev)?;
+        remote_cmd::exec(&self.session, &command)?.into_result()?;
+        Ok(())
+    }
+
+    /// ノードを作成するコマンド。
+    /// FIFOは"mkfifo"、デバイスは"mknod"、ソケットは、コマンドがないのでperlで作る。
+    /// modeはファイルの種類を含み、permはumask適用後の許可属性。対応しない種類はEINVAL。
+    fn mknod_command(path: &Path, mode: u32, perm: u32, rdev: u32) -> Result<String, Error> {
+        /// UNIXドメインソケットをbindして、ファイルを作るperlスクリプト
+        const SOCKET_SCRIPT: &str = r#"use Socket;
+socket(my $s, AF_UNIX, SOCK_STREAM, 0) or die "$!\n";
+bind($s, pack_sockaddr_un($ARGV[0])) or die "$!\n";"#;
+        let path = remote_cmd::quote(path)?;
+        let command = match mode & libc::S_IFMT {
+            libc::S_IFIFO => format!("mkfifo -m {perm:o} -- {path}"),
+            t @ (libc::S_IFCHR | libc::S_IFBLK) => {
+                let dev = rdev as libc::dev_t;
+                format!(
+                    "mknod -m {perm:o} -- {path} {} {} {}",
+                    if t == libc::S_IFCHR { 'c' } else { 'b' },
+                    libc::major(dev),
+                    libc::minor(dev)
+                )
+            }
+            libc::S_IFSOCK => format!(
+                "perl -e {} -- {path} && chmod {perm:o} -- {path}",
+                remote_cmd::quote(SOCKET_SCRIPT)?
+            ),
+            _ => return Err(Error(libc::EINVAL)),
+        };
+        Ok(command)
+    }
+
+    /// 移動先が存在すれば置換するrename。
+    /// SFTPv3のrenameは移動先が存在すると失敗する。(OVERWRITEフラグは効かない)
+    /// まずSFTPで試し、移動先が存在して失敗した時だけ、置換が原子的に行われるよう、リモートのrename(2)を使う。
+    fn rename_overwrite(&self, old_path: &Path, new_path: &Path) -> Result<(), Error> {
+        let Err(e) = self
+            .sftp
+            .rename(old_path, new_path, Some(ssh2::RenameFlags::NATIVE))
+        else {
+            return Ok(());
+        };
+        if self.sftp.lstat(new_path).is_err() {
+            return Err(e.into());
+        }
+        self.posix_rename_on_remote(old_path, new_path)
+    }
+
+    /// リモートのperlで、rename(2)による原子的な置換を行う。
+    /// (posix-rename@openssh.com拡張は、ssh2クレートから利用できないため)
+    /// mvと違い、ファイルシステムをまたぐ場合はコピーせず、EXDEVで失敗する。
+    fn posix_rename_on_remote(&self, old_path: &Path, new_path: &Path) -> Result<(), Error> {
+        const RENAME_SCRIPT: &str = r#"rename($ARGV[0], $ARGV[1]) or die "$!\n";"#;
+        let command = format!(
+            "perl -e {} -- {} {}",
+            remote_cmd::quote(RENAME_SCRIPT)?,
+            remote_cmd::quote(old_path)?,
+            remote_cmd::quote(new_path)?
+        );
+        remote_cmd::exec(&self.session, &command)?.into_result()?;
+        Ok(())
+    }
+
+    /// リモートの"dd"コマンドで、サーバー上でファイルの一部をコピーする。
+    /// (copy-data拡張は、ssh2クレートから利用できないため)
+    /// ddが存在しない、あるいはバイト単位の指定に対応していない(GNU以外のdd)場合は、EOPNOTSUPP。
+    fn copy_on_remote(
+        &self,
+        path_in: &Path,
+        offset_in: u64,
+        path_out: &Path,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        let command = Self::copy_command(path_in, offset_in, path_out, offset_out, len)?;
+        match remote_cmd::exec(&self.session, &command)?.into_result() {
+            Err(Error(libc::ENOSYS)) => Err(Error(libc::EOPNOTSUPP)),
+            r => r.map(|_| ()),
+        }
+    }
+
+    /// バイト単位の位置と長さで、ファイルの一部をコピーするddのコマンド。
+    fn copy_command(
+        path_in: &Path,
+        offset_in: u64,
+        path_out: &Path,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<String, Error> {
+        Ok(format!(
+            "dd if={} of={} bs=1M skip={offset_in} seek={offset_out} count={len} \
+             iflag=skip_bytes,count_bytes oflag=seek_bytes conv=notrunc",
+            remote_cmd::quote(path_in)?,
+            remote_cmd::quote(path_out)?
+        ))
+    }
+
+    /// --sparse-write時、全て0のデータの書き込みの代わりに、リモートに穴を残す。
+    /// ファイル内の部分は、リモートのfallocateで穴を開け、ファイル末尾以降の部分は、サイズの拡張のみとする。
+    /// fallocateはパスに対して行うので、パスがハンドルと同じファイルを指していない
+    /// (名前の変更や削除がされた)場合と、穴にしても意味がない短い書き込みは、エラーとし、通常の書き込みをさせる。
+    fn write_hole(
+        &self,
+        ino: u64,
+        file: &mut ssh2::File,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        const PUNCH_HOLE: i32 = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let stat = file.stat()?;
+        let size = stat.size.ok_or(Error(libc::EIO))?;
+        let hole = sparse::Hole::plan(size, offset, len).ok_or(Error(libc::EINVAL))?;
+        if let Some((offset, len)) = hole.punch {
+            let path = self.inodes.get_path(ino).ok_or(Error(libc::ENOENT))?;
+            if !sparse::same_file(&stat, &self.sftp.lstat(&path)?) {
+                return Err(Error(libc::ESTALE));
+            }
+            sparse::fallocate(&self.session, &path, offset, len, PUNCH_HOLE)?;
+        }
+        if let Some(end) = hole.extend {
+            let stat = ssh2::FileStat {
+                size: Some(end),
+                uid: None,
+                gid: None,
+                perm: None,
+                atime: None,
+                mtime: None,
+            };
+            file.setstat(stat)?;
+        }
+        Ok(())
+    }
+
+    /// リモートの"sync"コマンドで、指定したパスをディスクに同期させる。
+    /// fsync@openssh.com拡張が使えないサーバー及び、ディレクトリの同期に使用する。
+    fn sync_on_remote(&self, path: &Path) -> Result<(), Error> {
+        let command = format!("sync -- {}", remote_cmd::quote(path)?);
+        remote_cmd::exec(&self.session, &command)?.into_result()?;
+        Ok(())
+    }
 }
 
 impl Filesystem for Sshfs {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        use fuser::consts::{
+            FUSE_DO_READDIRPLUS, FUSE_EXPORT_SUPPORT, FUSE_FLOCK_LOCKS, FUSE_POSIX_LOCKS,
+            FUSE_READDIRPLUS_AUTO,
+        };
+        if let Err(e) = config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO) {
+            warn!(
+                "[init] カーネルがreaddirplusに対応していない。 flags={:x}",
+                e
+            );
+        }
+        if self.export {
+            if let Err(e) = config.add_capabilities(FUSE_EXPORT_SUPPORT) {
+                warn!(
+                    "[init] カーネルがエクスポートに対応していない。 flags={:x}",
+                    e
+                );
+            }
+        }
+        // localでは、ロックはカーネル内で処理させる。
+        // remoteでも、flockはPOSIXロックと区別できないので、カーネル内で処理させる。
+        let locks = match self.locking {
+            Locking::Local => 0,
+            Locking::Remote => FUSE_POSIX_LOCKS,
+            Locking::None => FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS,
+        };
+        if locks != 0 {
+            if let Err(e) = config.add_capabilities(locks) {
+                warn!(
+                    "[init] カーネルがロックの委譲に対応していない。 flags={:x}",
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if name == "." || name == ".." {
+            match self.lookup_dot(parent, name, req.uid(), req.gid()) {
+                Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, self.generation),
+                Err(e) => reply.error(e.0),
+            }
+            return;
+        }
+        // リモートの文字コードで表せない名前のファイルは、存在しない。
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(_) => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
         let Some(mut path) = self.inodes.get_path(parent) else {
             debug!("[lookup] 親ディレクトリの検索に失敗 inode={}", parent);
             reply.error(ENOENT);
             return;
         };
         path.push(Path::new(name));
-        match self.getattr_from_ssh2(&path, req.uid(), req.gid()) {
-            Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, 0),
+        match self.lookup_from_ssh2(parent, name, &path, req.uid(), req.gid()) {
+            Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, self.generation),
             Err(e) => {
                 reply.error(e.0);
             }
         };
     }
 
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.forget_inode(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuser::fuse_forget_one]) {
+        for node in nodes {
+            self.forget_inode(node.nodeid, node.nlookup);
+        }
+    }
+
     fn getattr(&mut self, req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         let Some(path) = self.inodes.get_path(ino) else {
             debug!("[getattr] path取得失敗: inode={}", ino);
             reply.error(ENOENT);
             return;
         };
-        match self.getattr_from_ssh2(&path, req.uid(), req.gid()) {
+        match self.getattr_from_ssh2(ino, &path, req.uid(), req.gid()) {
             Ok(attr) => {
                 //debug!("[getattr]retrun attr: {:?}", &attr);
                 reply.attr(&Duration::from_secs(1), &attr);
@@ -130,113 +819,208 @@ impl Filesystem for Sshfs {
         };
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
+    fn access(&mut self, _req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
         let Some(path) = self.inodes.get_path(ino) else {
-            reply.error(libc::ENOENT);
+            reply.error(ENOENT);
             return;
         };
-        match self.sftp.readdir(&path) {
-            Ok(mut dir) => {
-                let cur_file_attr = ssh2::FileStat {
-                    size: None,
-                    uid: None,
-                    gid: None,
-                    perm: Some(libc::S_IFDIR),
-                    atime: None,
-                    mtime: None,
-                }; // "." ".."の解決用。 attr ディレクトリであることのみを示す。
-                dir.insert(0, (Path::new("..").into(), cur_file_attr.clone()));
-                dir.insert(0, (Path::new(".").into(), cur_file_attr));
-                let mut i = offset + 1;
-                for f in dir.iter().skip(offset as usize) {
-                    let ino = if f.0 == Path::new("..") || f.0 == Path::new(".") {
-                        1
-                    } else {
-                        self.inodes.add(&f.0)
-                    };
-                    let name = match f.0.file_name() {
-                        Some(n) => n,
-                        None => f.0.as_os_str(),
-                    };
-                    let filetype = &f.1.file_type();
-                    let filetype = match Self::conv_file_kind_ssh2fuser(filetype) {
-                        Ok(t) => t,
-                        Err(e) => {
-                            warn!(
-                                "[readdir]ファイルタイプ解析失敗: inode={}, name={:?}",
-                                ino, name
-                            );
-                            reply.error(e.0);
-                            return;
-                        }
-                    };
-                    if reply.add(ino, i, filetype, name) {
-                        break;
-                    }
-                    i += 1;
-                }
-                reply.ok();
-            }
+        let stat = match self.sftp.stat(&path) {
+            Ok(s) => s,
             Err(e) => {
-                warn!("[readdir]ssh2::readdir内でエラー発生-- {:?}", e);
                 reply.error(Error::from(e).0);
+                return;
             }
         };
+        // リモートユーザーが取得できていなければ、判定はサーバーでの実操作に任せる。
+        match &self.remote_user {
+            Some(user) if !user.can_access(&stat, mask) => reply.error(libc::EACCES),
+            _ => reply.ok(),
+        }
     }
 
-    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
         let Some(path) = self.inodes.get_path(ino) else {
-            error!("[readlink] 親ディレクトリの検索に失敗 {ino}");
             reply.error(libc::ENOENT);
             return;
         };
-        match self.sftp.readlink(&path) {
-            Ok(p) => {
-                //debug!("[readlink] ret_path => {:?}", &p);
-                reply.data(p.as_os_str().to_string_lossy().as_bytes());
+        match self.sftp.opendir(&path) {
+            Ok(dir) => {
+                let fh = self.dhandls.add_dir(dir);
+                reply.opened(fh, 0);
             }
             Err(e) => {
-                //debug!("[readlink] ssh2::readlink error => {e:?}");
+                warn!("[opendir]ssh2::opendir内でエラー発生-- {:?}", e);
                 reply.error(Error::from(e).0);
             }
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        let Some(file_name) = self.inodes.get_path(ino) else {
-            reply.error(libc::ENOENT);
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(dir_mutex) = self.dhandls.get_dir(fh) else {
+            reply.error(libc::EBADF);
             return;
         };
-
-        let mut flags_ssh2 = OpenFlags::empty();
-        if flags & libc::O_WRONLY != 0 {
-            flags_ssh2.insert(OpenFlags::WRITE);
-        } else if flags & libc::O_RDWR != 0 {
-            flags_ssh2.insert(OpenFlags::READ);
-            flags_ssh2.insert(OpenFlags::WRITE);
-        } else {
-            flags_ssh2.insert(OpenFlags::READ);
-        }
-        if flags & libc::O_APPEND != 0 {
-            flags_ssh2.insert(OpenFlags::APPEND);
+        let mut dir = dir_mutex.lock().unwrap();
+        self.load_remote_inos(ino, &mut dir);
+        for i in offset.max(0).. {
+            let (name, stat) = match Self::dir_entry(&mut dir, i) {
+                Ok(Some(e)) => e,
+                Ok(None) => break,
+                Err(e) => {
+                    reply.error(e.0);
+                    return;
+                }
+            };
+            // readdirの応答はカーネルの参照を生まないので、未登録のパスは登録しない。
+            let entry_ino = if i < 2 {
+                self.dot_ino(ino, i)
+            } else {
+                self.inodes
+                    .get_inode(ino, &name)
+                    .or_else(|| dir.remote_ino(&name))
+                    .unwrap_or(FUSE_UNKNOWN_INO)
+            };
+            let filetype = match Self::conv_file_kind_ssh2fuser(&stat.file_type()) {
+                Ok(t) => t,
+                Err(e) => {
+                    warn!(
+                        "[readdir]ファイルタイプ解析失敗: inode={}, name={:?}",
+                        entry_ino, name
+                    );
+                    reply.error(e.0);
+                    return;
+                }
+            };
+            let Some(local_name) = self.local_name(&name) else {
+                continue;
+            };
+            if reply.add(entry_ino, i + 1, filetype, local_name) {
+                break;
+            }
         }
-        if flags & libc::O_CREAT != 0 {
-            flags_ssh2.insert(OpenFlags::CREATE);
-        }
-        if flags & libc::O_TRUNC != 0 {
-            flags_ssh2.insert(OpenFlags::TRUNCATE);
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        let Some(dir_mutex) = self.dhandls.get_dir(fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let mut dir = dir_mutex.lock().unwrap();
+        self.load_remote_inos(ino, &mut dir);
+        self.load_remote_stats(ino, &mut dir);
+        for i in offset.max(0).. {
+            let (name, stat) = match Self::dir_entry(&mut dir, i) {
+                Ok(Some(e)) => e,
+                Ok(None) => break,
+                Err(e) => {
+                    reply.error(e.0);
+                    return;
+                }
+            };
+            // 属性は、readdirの結果に含まれているものをそのまま使う。(lstatは発行しない)
+            let (uid, gid) = self.idmap.local_ids(&stat, req.uid(), req.gid());
+            let mut attr = match Self::make_attr(0, &stat, uid, gid) {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("[readdirplus]ファイルタイプ解析失敗: name={:?}", name);
+                    reply.error(e.0);
+                    return;
+                }
+            };
+            if let Some(s) = dir.remote_stat(&name) {
+                s.apply(&mut attr);
+            }
+            let Some(local_name) = self.local_name(&name) else {
+                continue;
+            };
+            // "." ".."以外のエントリは、lookupと同様にカーネルの参照数を増やす。
+            attr.ino = if i < 2 {
+                self.dot_ino(ino, i)
+            } else {
+                match dir.remote_ino(&name) {
+                    Some(r) => self.inodes.lookup_with_ino(ino, &name, r),
+                    None => self.inodes.lookup(ino, &name),
+                }
+            };
+            if reply.add(
+                attr.ino,
+                i + 1,
+                local_name,
+                &Duration::from_secs(1),
+                &attr,
+                self.generation,
+            ) {
+                // バッファが一杯で返せなかったエントリの参照は、取り消しておく。
+                if i >= 2 {
+                    self.inodes.forget(attr.ino, 1);
+                }
+                break;
+            }
         }
-        if flags & libc::O_EXCL != 0 {
-            flags_ssh2.insert(OpenFlags::EXCLUSIVE);
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.dhandls.del_dir(fh);
+        reply.ok();
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let Some(path) = self.inodes.get_path(ino) else {
+            error!("[readlink] 親ディレクトリの検索に失敗 {ino}");
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.sftp.readlink(&path) {
+            Ok(p) => {
+                //debug!("[readlink] ret_path => {:?}", &p);
+                // マウント内を指す絶対パスは、リンクの位置からの相対パスにする。
+                let p = if self.transform_symlinks {
+                    symlink::to_local(&self.top_path, &path, &p).unwrap_or(p)
+                } else {
+                    p
+                };
+                match self.charset.path_to_local(&p) {
+                    Ok(p) => reply.data(p.as_os_str().as_bytes()),
+                    Err(e) => reply.error(e.0),
+                }
+            }
+            Err(e) => {
+                //debug!("[readlink] ssh2::readlink error => {e:?}");
+                reply.error(Error::from(e).0);
+            }
         }
+    }
 
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let Some(file_name) = self.inodes.get_path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let flags_ssh2 = Self::conv_open_flags(flags);
         debug!(
             "[open] filename='{:?}', openflag = {:?}, bit = {:x}",
             &file_name,
@@ -263,6 +1047,97 @@ impl Filesystem for Sshfs {
         }
     }
 
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
+        let Some(mut file_name) = self.inodes.get_path(parent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        file_name.push(name);
+
+        // 作成とオープンを、一回のリモートでのオープンで行う。(O_EXCLも原子的に効く)
+        // O_EXCLのない場合も、まずO_EXCLで開き、作成したかどうかを区別する。
+        // (SFTPv3では既存ファイルのエラーを区別できないので、失敗したらO_EXCLなしで開き直す)
+        let flags_ssh2 = Self::conv_open_flags(flags) | OpenFlags::CREATE;
+        let mode = (mode & !umask & 0o7777) as i32;
+        debug!(
+            "[create] filename='{:?}', openflag = {:?}, mode = {:o}",
+            &file_name, &flags_ssh2, mode
+        );
+        let exclusive = flags_ssh2 | OpenFlags::EXCLUSIVE;
+        let (file, created) = match self
+            .sftp
+            .open_mode(&file_name, exclusive, mode, OpenType::File)
+        {
+            Ok(f) => (Ok(f), true),
+            Err(_) if !flags_ssh2.contains(OpenFlags::EXCLUSIVE) => (
+                self.sftp
+                    .open_mode(&file_name, flags_ssh2, mode, OpenType::File),
+                false,
+            ),
+            Err(e) => (Err(e), false),
+        };
+        let file = match file {
+            Ok(f) => f,
+            Err(e) => {
+                reply.error(Error::from(e).0);
+                return;
+            }
+        };
+        let fh = self.fhandls.add_file(file);
+        match self.lookup_from_ssh2(parent, name, &file_name, req.uid(), req.gid()) {
+            // 応答のフラグは、FOPEN_*。(オープンのフラグではない)
+            Ok(attr) => reply.created(&Duration::from_secs(1), &attr, self.generation, fh, 0),
+            Err(e) => {
+                self.fhandls.del_file(fh);
+                // 作成したファイルは、カーネルに見えないまま残らないよう削除する。
+                if created {
+                    if let Err(e) = self.sftp.unlink(&file_name) {
+                        warn!(
+                            "[create] 作成したファイルの削除失敗 {:?} -- {:?}",
+                            &file_name, e
+                        );
+                    }
+                }
+                reply.error(e.0);
+            }
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        // close(2)時には、そのownerのPOSIXロックを解除する。
+        if self.locking == Locking::Remote {
+            self.locks
+                .lock()
+                .unwrap()
+                .release_owner(&self.session, ino, lock_owner);
+        }
+        reply.ok();
+    }
+
     fn release(
         &mut self,
         _req: &Request<'_>,
@@ -325,7 +1200,7 @@ impl Filesystem for Sshfs {
     fn write(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         offset: i64,
         data: &[u8],
@@ -340,235 +1215,763 @@ impl Filesystem for Sshfs {
         };
         let file = &mut file_mutex.lock().unwrap();
 
+        if self.sparse_write && sparse::is_zero(data) {
+            match self.write_hole(ino, file, offset as u64, data.len() as u64) {
+                Ok(_) => {
+                    reply.written(data.len() as u32);
+                    return;
+                }
+                Err(e) => debug!("[write] 穴にできない。通常の書き込みを行う。 -- {:?}", e),
+            }
+        }
+
         if let Err(e) = file.seek(std::io::SeekFrom::Start(offset as u64)) {
             reply.error(Error::from(e).0);
             return;
         }
-        let mut buf = data;
-        while !buf.is_empty() {
-            let cnt = match file.write(buf) {
-                Ok(cnt) => cnt,
-                Err(e) => {
-                    reply.error(Error::from(e).0);
-                    return;
-                }
-            };
-            buf = &buf[cnt..];
+        let mut buf = data;
+        while !buf.is_empty() {
+            let cnt = match file.write(buf) {
+                Ok(cnt) => cnt,
+                Err(e) => {
+                    reply.error(Error::from(e).0);
+                    return;
+                }
+            };
+            buf = &buf[cnt..];
+        }
+        reply.written(data.len() as u32);
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let Some(file_mutex) = self.fhandls.get_file(fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let ret = file_mutex.lock().unwrap().fsync();
+        match ret {
+            Ok(_) => reply.ok(),
+            Err(e) if e.code() == ErrorCode::SFTP(SSH_FX_OP_UNSUPPORTED) => {
+                debug!("[fsync] fsync@openssh.com使用不可。リモートのsyncで代替する。");
+                let Some(path) = self.inodes.get_path(ino) else {
+                    reply.error(libc::ENOENT);
+                    return;
+                };
+                match self.sync_on_remote(&path) {
+                    Ok(_) => reply.ok(),
+                    Err(e) => reply.error(e.0),
+                }
+            }
+            Err(e) => {
+                warn!("[fsync] fsync失敗 -- {:?}", &e);
+                reply.error(Error::from(e).0);
+            }
+        }
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        // SFTPのディレクトリハンドルはfsync@openssh.comの対象外なので、常にリモートのsyncを使う。
+        let Some(path) = self.inodes.get_path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.sync_on_remote(&path) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        if whence == libc::SEEK_DATA || whence == libc::SEEK_HOLE {
+            let Some(file_mutex) = self.fhandls.get_file(fh) else {
+                reply.error(libc::EBADF);
+                return;
+            };
+            let Some(path) = self.inodes.get_path(ino) else {
+                reply.error(libc::ENOENT);
+                return;
+            };
+            let file = &mut file_mutex.lock().unwrap();
+            match self.seek_data_hole(&path, file, offset, whence == libc::SEEK_HOLE) {
+                Ok(pos) => {
+                    let _ = file.seek(SeekFrom::Start(pos));
+                    reply.offset(pos as i64);
+                }
+                Err(e) => reply.error(e.0),
+            }
+            return;
+        }
+        let seek_from = match whence {
+            libc::SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            libc::SEEK_CUR => SeekFrom::Current(offset),
+            libc::SEEK_END => SeekFrom::End(offset),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        let Some(file_mutex) = self.fhandls.get_file(fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let file = &mut file_mutex.lock().unwrap();
+        let pos = match file.seek(seek_from) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(Error::from(e).0);
+                return;
+            }
+        };
+        reply.offset(pos as i64);
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
+        let mode = mode & (!umask | libc::S_IFMT);
+        let Some(mut new_name) = self.inodes.get_path(parent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        new_name.push(name);
+        if mode & libc::S_IFMT == libc::S_IFREG {
+            if let Err(e) =
+                self.sftp
+                    .open_mode(&new_name, OpenFlags::CREATE, mode as i32, OpenType::File)
+            {
+                reply.error(Error::from(e).0);
+                return;
+            }
+        } else {
+            // SFTPでは、通常ファイル以外は作れない。
+            match self.mknod_on_remote(&new_name, mode, mode & 0o7777, rdev) {
+                Ok(_) => {}
+                Err(Error(libc::ENOSYS)) => {
+                    warn!(
+                        "[mknod] リモートのコマンドが使えないので、作成できない。 {:?}",
+                        &new_name
+                    );
+                    reply.error(libc::EPERM);
+                    return;
+                }
+                Err(e) => {
+                    reply.error(e.0);
+                    return;
+                }
+            }
+        }
+        let new_attr = match self.lookup_from_ssh2(parent, name, &new_name, req.uid(), req.gid()) {
+            Ok(a) => a,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        reply.entry(&Duration::from_secs(1), &new_attr, self.generation);
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(_) => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
+        let Some(mut path) = self.inodes.get_path(parent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        path.push(name);
+        match self.sftp.unlink(&path) {
+            Ok(_) => {
+                self.remove_inode(parent, name);
+                reply.ok();
+            }
+            Err(e) => reply.error(Error::from(e).0),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
+        let Some(mut path) = self.inodes.get_path(parent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        path.push(name);
+
+        let mode = (mode & (!umask) & 0o777) as i32;
+
+        match self.sftp.mkdir(&path, mode) {
+            Ok(_) => match self.lookup_from_ssh2(parent, name, &path, req.uid(), req.gid()) {
+                Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, self.generation),
+                Err(e) => reply.error(e.0),
+            },
+            Err(e) => reply.error(Error::from(e).0),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(_) => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
+        let Some(mut path) = self.inodes.get_path(parent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        path.push(name);
+        match self.sftp.rmdir(&path) {
+            Ok(_) => {
+                self.remove_inode(parent, name);
+                reply.ok()
+            }
+            Err(e) => {
+                if e.code() == ErrorCode::Session(-31) {
+                    // ssh2ライブラリの返すエラーが妙。置換しておく。
+                    reply.error(libc::ENOTEMPTY);
+                } else {
+                    reply.error(Error::from(e).0)
+                }
+            }
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
+        // マウントポイント以下を指す絶対パスは、リモートの絶対パスにする。
+        let (top, link) = match self
+            .transform_symlinks
+            .then(|| symlink::strip_mount_point(&self.mount_point, link))
+            .flatten()
+        {
+            Some(rest) => (Some(&self.top_path), rest),
+            None => (None, link),
+        };
+        let link = match self.charset.path_to_remote(link) {
+            Ok(l) => l,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let link = match top {
+            Some(top) if !link.as_os_str().is_empty() => Cow::Owned(top.join(link)),
+            Some(top) => Cow::Borrowed(top.as_path()),
+            None => link,
+        };
+        let Some(mut target) = self.inodes.get_path(parent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        target.push(name);
+        match self.sftp.symlink(&link, &target) {
+            Ok(_) => match self.lookup_from_ssh2(parent, name, &target, req.uid(), req.gid()) {
+                Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, self.generation),
+                Err(e) => reply.error(e.0),
+            },
+            Err(e) => reply.error(Error::from(e).0),
+        }
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let newname = match self.charset.to_remote(newname) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let newname: &OsStr = &newname;
+        let Some(src) = self.inodes.get_path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let Some(mut dst) = self.inodes.get_path(newparent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        dst.push(newname);
+        // ssh2クレートはhardlink@openssh.com拡張を公開していないので、リモートのlnを使う。
+        let nlink = match self.link_on_remote(&src, &dst) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!(
+                    "[link] ハードリンク作成失敗 {:?} -> {:?} -- {:?}",
+                    &src, &dst, &e
+                );
+                reply.error(e.0);
+                return;
+            }
+        };
+        // 新しい名前は、リンク元と同じinodeの別名として登録する。
+        // 返す属性で、カーネルが持つリンク元の属性も更新されるので、リンク数を反映しておく。
+        match self.getattr_from_ssh2(ino, &dst, req.uid(), req.gid()) {
+            Ok(mut attr) => {
+                if let Some(nlink) = nlink {
+                    attr.nlink = nlink;
+                }
+                self.inodes.lookup_alias(newparent, newname, ino);
+                reply.entry(&Duration::from_secs(1), &attr, self.generation);
+            }
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let Some(filename) = self.inodes.get_path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let owner = match self.remote_owner(&filename, uid, gid, req.uid(), req.gid()) {
+            Ok(o) => o,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let stat = ssh2::FileStat {
+            size,
+            uid: owner.map(|o| o.0),
+            gid: owner.map(|o| o.1),
+            perm: mode,
+            atime: atime.map(|t| {
+                Self::conv_timeornow2systemtime(&t)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            mtime: mtime.map(|t| {
+                Self::conv_timeornow2systemtime(&t)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+        };
+        match self.sftp.setstat(&filename, stat) {
+            Ok(_) => {
+                if let Some(owner) = owner {
+                    // サーバーによっては、所有者の変更を黙って無視するので、結果を確認する。
+                    match self.sftp.lstat(&filename) {
+                        Ok(s) if (s.uid, s.gid) == (Some(owner.0), Some(owner.1)) => {}
+                        Ok(_) => {
+                            reply.error(libc::EPERM);
+                            return;
+                        }
+                        Err(e) => {
+                            reply.error(Error::from(e).0);
+                            return;
+                        }
+                    }
+                }
+                let stat = self.getattr_from_ssh2(ino, &filename, req.uid(), req.gid());
+                match stat {
+                    Ok(s) => reply.attr(&Duration::from_secs(1), &s),
+                    Err(e) => reply.error(e.0),
+                }
+            }
+            Err(e) => match Error::from(e) {
+                // 所有者の変更の拒否は、chown(2)と同じくEPERMとする。
+                Error(libc::EACCES) if owner.is_some() => reply.error(libc::EPERM),
+                e => reply.error(e.0),
+            },
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match sparse::fallocate(&self.session, &path, offset as u64, length as u64, mode) {
+            Ok(_) => reply.ok(),
+            Err(Error(libc::ENOSYS)) => {
+                // 領域を予約できないので、サイズの拡張だけで成功とはしない。
+                debug!("[fallocate] リモートのfallocateが使えない。");
+                reply.error(libc::EOPNOTSUPP);
+            }
+            Err(e) => {
+                warn!("[fallocate] 失敗 {:?} mode={:x} -- {:?}", &path, mode, &e);
+                reply.error(e.0);
+            }
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        _pid: u32,
+        reply: fuser::ReplyLock,
+    ) {
+        if self.locking != Locking::Remote {
+            reply.error(libc::ENOLCK);
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let result = self.locks.lock().unwrap().getlk(
+            &self.session,
+            &path,
+            ino,
+            lock_owner,
+            start,
+            end,
+            typ,
+        );
+        match result {
+            Ok((start, end, typ, pid)) => reply.locked(start, end, typ, pid),
+            Err(Error(libc::ENOSYS)) => {
+                warn!("[getlk] リモートでperlが使えないので、ロックできない。");
+                reply.error(libc::ENOLCK);
+            }
+            Err(e) => reply.error(e.0),
         }
-        reply.written(data.len() as u32);
     }
 
-    fn lseek(
+    fn setlk(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        offset: i64,
-        whence: i32,
-        reply: fuser::ReplyLseek,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: fuser::ReplyEmpty,
     ) {
-        let seek_from = match whence {
-            libc::SEEK_SET => {
-                if offset >= 0 {
-                    SeekFrom::Start(offset as u64)
-                } else {
-                    reply.error(libc::EINVAL);
-                    return;
-                }
+        match self.locking {
+            Locking::Remote => {}
+            // 解除は、常に成功させる。
+            _ if typ == libc::F_UNLCK => {
+                reply.ok();
+                return;
             }
-            libc::SEEK_CUR => SeekFrom::Current(offset),
-            libc::SEEK_END => SeekFrom::End(offset),
             _ => {
-                reply.error(libc::EINVAL);
+                reply.error(libc::ENOLCK);
                 return;
             }
-        };
-        let Some(file_mutex) = self.fhandls.get_file(fh) else {
-            reply.error(libc::EBADF);
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
+            reply.error(libc::ENOENT);
             return;
         };
-        let file = &mut file_mutex.lock().unwrap();
-        let pos = match file.seek(seek_from) {
-            Ok(p) => p,
-            Err(e) => {
-                reply.error(Error::from(e).0);
-                return;
-            }
-        };
-        reply.offset(pos as i64);
+        // 応答は、待ち合わせ(sleep)の場合、ロックが解除されるまで保留されることがある。
+        self.locks.lock().unwrap().setlk(
+            &self.session,
+            &path,
+            ino,
+            lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+            sleep,
+            reply,
+        );
     }
 
-    fn mknod(
+    fn copy_file_range(
         &mut self,
-        req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        umask: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
+        _req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
     ) {
-        if mode & libc::S_IFMT != libc::S_IFREG {
-            reply.error(libc::EPERM);
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            reply.error(libc::EINVAL);
             return;
         }
-        let mode = mode & (!umask | libc::S_IFMT);
-        let Some(mut new_name) = self.inodes.get_path(parent) else {
+        if self.fhandls.get_file(fh_in).is_none() || self.fhandls.get_file(fh_out).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
+        let (Some(path_in), Some(path_out)) =
+            (self.inodes.get_path(ino_in), self.inodes.get_path(ino_out))
+        else {
             reply.error(libc::ENOENT);
             return;
         };
-        new_name.push(name);
-        if let Err(e) =
-            self.sftp
-                .open_mode(&new_name, OpenFlags::CREATE, mode as i32, OpenType::File)
-        {
-            reply.error(Error::from(e).0);
+        let size_in = match self.sftp.lstat(&path_in) {
+            Ok(s) => s.size.unwrap_or(0),
+            Err(e) => {
+                reply.error(Error::from(e).0);
+                return;
+            }
+        };
+        // 返せるコピー量はu32まで。残りは、カーネルが再度要求してくる。
+        let len = len
+            .min(size_in.saturating_sub(offset_in as u64))
+            .min(u32::MAX as u64);
+        if len == 0 {
+            reply.written(0);
             return;
         }
-        let new_attr = match self.getattr_from_ssh2(&new_name, req.uid(), req.gid()) {
-            Ok(a) => a,
+        match self.copy_on_remote(
+            &path_in,
+            offset_in as u64,
+            &path_out,
+            offset_out as u64,
+            len,
+        ) {
+            Ok(_) => reply.written(len as u32),
+            Err(Error(libc::EOPNOTSUPP)) => {
+                // カーネルに、通常のread/writeによるコピーへ切り替えさせる。
+                debug!("[copy_file_range] リモートのddが使えない。");
+                reply.error(libc::EOPNOTSUPP);
+            }
             Err(e) => {
+                warn!(
+                    "[copy_file_range] リモートでのコピー失敗 {:?} -> {:?} -- {:?}",
+                    &path_in, &path_out, &e
+                );
                 reply.error(e.0);
-                return;
             }
-        };
-        reply.entry(&Duration::from_secs(1), &new_attr, 0);
+        }
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        let Some(mut path) = self.inodes.get_path(parent) else {
-            reply.error(libc::ENOENT);
-            return;
-        };
-        path.push(name);
-        match self.sftp.unlink(&path) {
-            Ok(_) => {
-                self.inodes.del_inode_with_path(&path);
-                reply.ok();
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        if self.sftp_statvfs {
+            match StatFs::from_sftp(&self.sftp, &self.top_path) {
+                Ok(s) => {
+                    Self::reply_statfs(reply, &s);
+                    return;
+                }
+                Err(e) => {
+                    debug!(
+                        "[statfs] fstatvfs@openssh.com使用不可。以後はdfで代替する。 -- {:?}",
+                        e
+                    );
+                    self.sftp_statvfs = false;
+                }
+            }
+        }
+        match StatFs::from_df(&self.session, &self.top_path) {
+            Ok(s) => Self::reply_statfs(reply, &s),
+            Err(e) => {
+                warn!("[statfs] dfによる取得も失敗 -- {:?}", e);
+                reply.error(e.0);
             }
-            Err(e) => reply.error(Error::from(e).0),
         }
     }
 
-    fn mkdir(
+    fn getxattr(
         &mut self,
-        req: &Request<'_>,
-        parent: u64,
+        _req: &Request<'_>,
+        ino: u64,
         name: &OsStr,
-        mode: u32,
-        umask: u32,
-        reply: ReplyEntry,
+        size: u32,
+        reply: fuser::ReplyXattr,
     ) {
-        let Some(mut path) = self.inodes.get_path(parent) else {
+        if !self.xattr {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
             reply.error(libc::ENOENT);
             return;
         };
-        path.push(name);
-
-        let mode = (mode & (!umask) & 0o777) as i32;
-
-        match self.sftp.mkdir(&path, mode) {
-            Ok(_) => match self.getattr_from_ssh2(&path, req.uid(), req.gid()) {
-                Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, 0),
-                Err(e) => reply.error(e.0),
-            },
-            Err(e) => reply.error(Error::from(e).0),
+        if let Some(errno) = self.xattr_missing.get(ino, name) {
+            reply.error(errno);
+            return;
+        }
+        match xattr::get(&self.session, &path, name) {
+            Ok(value) => Self::reply_xattr(reply, size, &value),
+            Err(e) => {
+                self.xattr_missing.insert(ino, name, e.0);
+                reply.error(e.0);
+            }
         }
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        let Some(mut path) = self.inodes.get_path(parent) else {
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        if !self.xattr {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
             reply.error(libc::ENOENT);
             return;
         };
-        path.push(name);
-        match self.sftp.rmdir(&path) {
-            Ok(_) => {
-                self.inodes.del_inode_with_path(&path);
-                reply.ok()
-            }
-            Err(e) => {
-                if e.code() == ErrorCode::Session(-31) {
-                    // ssh2ライブラリの返すエラーが妙。置換しておく。
-                    reply.error(libc::ENOTEMPTY);
-                } else {
-                    reply.error(Error::from(e).0)
-                }
-            }
+        // 一覧は毎回リモートから取得するので、他のホストでの変更を反映するため、キャッシュを捨てる。
+        self.xattr_missing.clear(ino);
+        match xattr::list(&self.session, &path) {
+            Ok(list) => Self::reply_xattr(reply, size, &list),
+            Err(e) => reply.error(e.0),
         }
     }
 
-    fn symlink(
+    fn setxattr(
         &mut self,
-        req: &Request<'_>,
-        parent: u64,
+        _req: &Request<'_>,
+        ino: u64,
         name: &OsStr,
-        link: &Path,
-        reply: ReplyEntry,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: fuser::ReplyEmpty,
     ) {
-        let Some(mut target) = self.inodes.get_path(parent) else {
+        if !self.xattr {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+        if position != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
             reply.error(libc::ENOENT);
             return;
         };
-        target.push(name);
-        match self.sftp.symlink(link, &target) {
-            Ok(_) => match self.getattr_from_ssh2(&target, req.uid(), req.gid()) {
-                Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, 0),
-                Err(e) => reply.error(e.0),
-            },
-            Err(e) => reply.error(Error::from(e).0),
+        self.xattr_missing.remove(ino, name);
+        match xattr::set(&self.session, &path, name, value, flags) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.0),
         }
     }
 
-    fn setattr(
+    fn removexattr(
         &mut self,
-        req: &Request<'_>,
+        _req: &Request<'_>,
         ino: u64,
-        mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        size: Option<u64>,
-        atime: Option<fuser::TimeOrNow>,
-        mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
-        _chgtime: Option<std::time::SystemTime>,
-        _bkuptime: Option<std::time::SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
     ) {
-        let stat = ssh2::FileStat {
-            size,
-            uid: None,
-            gid: None,
-            perm: mode,
-            atime: atime.map(|t| {
-                Self::conv_timeornow2systemtime(&t)
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs()
-            }),
-            mtime: mtime.map(|t| {
-                Self::conv_timeornow2systemtime(&t)
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs()
-            }),
-        };
-        let Some(filename) = self.inodes.get_path(ino) else {
-            reply.error(ENOENT);
+        if !self.xattr {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino) else {
+            reply.error(libc::ENOENT);
             return;
         };
-        match self.sftp.setstat(&filename, stat) {
-            Ok(_) => {
-                let stat = self.getattr_from_ssh2(&filename, req.uid(), req.gid());
-                match stat {
-                    Ok(s) => reply.attr(&Duration::from_secs(1), &s),
-                    Err(e) => reply.error(e.0),
-                }
-            }
-            Err(e) => reply.error(Error::from(e).0),
+        self.xattr_missing.remove(ino, name);
+        match xattr::remove(&self.session, &path, name) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.0),
         }
     }
 
@@ -582,6 +1985,22 @@ impl Filesystem for Sshfs {
         flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        let name = match self.charset.to_remote(name) {
+            Ok(n) => n,
+            Err(_) => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let name: &OsStr = &name;
+        let newname = match self.charset.to_remote(newname) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e.0);
+                return;
+            }
+        };
+        let newname: &OsStr = &newname;
         let Some(mut old_path) = self.inodes.get_path(parent) else {
             reply.error(libc::ENOENT);
             return;
@@ -594,12 +2013,32 @@ impl Filesystem for Sshfs {
         };
         new_path.push(newname);
 
+        let overwrite = flags & (libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE) == 0;
+        if overwrite {
+            match self.rename_overwrite(&old_path, &new_path) {
+                Ok(_) => {
+                    // 置換された移動先のinodeは、inodes.renameが配下ごと削除する。
+                    self.inodes.rename(parent, name, newparent, newname);
+                    reply.ok();
+                    return;
+                }
+                Err(Error(libc::ENOSYS)) => {
+                    debug!("[rename] リモートのperlが使えない。手動での置換に切り替える。");
+                }
+                Err(e) => {
+                    reply.error(e.0);
+                    return;
+                }
+            }
+        }
+
         let mut rename_flag = ssh2::RenameFlags::NATIVE;
         if flags & libc::RENAME_EXCHANGE != 0 {
             rename_flag.insert(ssh2::RenameFlags::ATOMIC);
         }
-        if flags & libc::RENAME_NOREPLACE == 0 {
-            // renameのOVERWRITEが効いてない。手動で消す。
+        if overwrite {
+            // 代替手段:移動先を手動で消してからrenameする。
+            // 削除とrenameの間は移動先が存在しない状態になり、rename失敗時は移動先が失われる。
             if let Ok(stat) = self.sftp.lstat(&new_path) {
                 if stat.is_dir() {
                     if let Err(e) = self.sftp.rmdir(&new_path) {
@@ -610,13 +2049,13 @@ impl Filesystem for Sshfs {
                     reply.error(Error::from(e).0);
                     return;
                 }
-                self.inodes.del_inode_with_path(&new_path);
+                self.remove_inode(newparent, newname);
             }
         }
 
         match self.sftp.rename(&old_path, &new_path, Some(rename_flag)) {
             Ok(_) => {
-                self.inodes.rename(&old_path, &new_path);
+                self.inodes.rename(parent, name, newparent, newname);
                 reply.ok();
             }
             Err(e) => reply.error(Error::from(e).0),
@@ -624,6 +2063,12 @@ impl Filesystem for Sshfs {
     }
 }
 
+/// readdirで、inode番号が未確定のエントリに返す値 (libfuseのFUSE_UNKNOWN_INOと同じ)
+const FUSE_UNKNOWN_INO: u64 = 0xffff_ffff;
+
+/// SFTPのステータスコード SSH_FX_OP_UNSUPPORTED (拡張機能が使えない場合等に返される)
+const SSH_FX_OP_UNSUPPORTED: i32 = 8;
+
 #[derive(Debug, Clone, Copy)]
 struct Error(i32);
 
@@ -697,3 +2142,98 @@ impl From<std::io::Error> for Error {
         Self(eno)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dir_entry_test() {
+        use dir_handle::test::FakeDir;
+        let mut dir = DirStream::new(FakeDir::new(&["a", "b"]));
+        let name = |dir: &mut DirStream<FakeDir>, i| Sshfs::dir_entry(dir, i).unwrap().map(|e| e.0);
+        assert_eq!(name(&mut dir, 0), Some(".".into()));
+        assert_eq!(name(&mut dir, 1), Some("..".into()));
+        assert_eq!(name(&mut dir, 2), Some("a".into()));
+        assert_eq!(name(&mut dir, 3), Some("b".into()));
+        assert_eq!(name(&mut dir, 4), None);
+        // "." ".."はディレクトリとして扱う
+        let (_, stat) = Sshfs::dir_entry(&mut dir, 1).unwrap().unwrap();
+        assert!(stat.is_dir());
+    }
+
+    #[test]
+    fn make_attr_test() {
+        use dir_handle::test::stat;
+        // readdirplusは、readdirの結果の属性からFileAttrを作る
+        let attr = Sshfs::make_attr(5, &stat(0o100640, 1000), 10, 20).unwrap();
+        assert_eq!(attr.ino, 5);
+        assert_eq!(attr.kind, fuser::FileType::RegularFile);
+        assert_eq!(attr.perm & 0o7777, 0o640);
+        assert_eq!(attr.size, 1000);
+        assert_eq!(attr.blocks, 2);
+        assert_eq!((attr.uid, attr.gid), (10, 20));
+        assert_eq!(attr.atime, UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(attr.mtime, UNIX_EPOCH + Duration::from_secs(1_700_000_001));
+        let attr = Sshfs::make_attr(6, &stat(0o040755, 0), 0, 0).unwrap();
+        assert_eq!(attr.kind, fuser::FileType::Directory);
+        assert_eq!(
+            Sshfs::make_attr(7, &stat(0o170000, 0), 0, 0).map_err(|e| e.0),
+            Err(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn mknod_command_test() {
+        let path = Path::new("/a/n");
+        let command = |mode, perm, rdev| Sshfs::mknod_command(path, mode, perm, rdev);
+        assert_eq!(
+            command(libc::S_IFIFO | 0o644, 0o640, 0).unwrap(),
+            "mkfifo -m 640 -- '/a/n'"
+        );
+        let rdev = libc::makedev(8, 1) as u32;
+        assert_eq!(
+            command(libc::S_IFBLK | 0o660, 0o660, rdev).unwrap(),
+            "mknod -m 660 -- '/a/n' b 8 1"
+        );
+        let rdev = libc::makedev(1, 3) as u32;
+        assert_eq!(
+            command(libc::S_IFCHR | 0o666, 0o600, rdev).unwrap(),
+            "mknod -m 600 -- '/a/n' c 1 3"
+        );
+        let socket = command(libc::S_IFSOCK | 0o755, 0o755, 0).unwrap();
+        assert!(socket.starts_with("perl -e 'use Socket;"));
+        assert!(socket.ends_with("' -- '/a/n' && chmod 755 -- '/a/n'"));
+        assert_eq!(
+            command(libc::S_IFDIR | 0o755, 0o755, 0).map_err(|e| e.0),
+            Err(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn copy_command_test() {
+        assert_eq!(
+            Sshfs::copy_command(Path::new("/a/in"), 10, Path::new("/a/o ut"), 20, 30).unwrap(),
+            "dd if='/a/in' of='/a/o ut' bs=1M skip=10 seek=20 count=30 \
+             iflag=skip_bytes,count_bytes oflag=seek_bytes conv=notrunc"
+        );
+    }
+
+    #[test]
+    fn link_command_test() {
+        assert_eq!(
+            Sshfs::link_command(Path::new("/a/b"), Path::new("/a/it's")).unwrap(),
+            r"ln -- '/a/b' '/a/it'\''s' && { ls -ldn -- '/a/it'\''s' || true; }"
+        );
+        assert_eq!(
+            Sshfs::parse_nlink(b"-rw-r--r-- 2 1000 1000 5 Jan  1 00:00 /a/b\n"),
+            Some(2)
+        );
+        assert_eq!(
+            Sshfs::parse_nlink(b"-rw-r--r--   12 0 0 5 Jan  1 00:00 /a/b\nx\n"),
+            Some(12)
+        );
+        assert_eq!(Sshfs::parse_nlink(b""), None);
+        assert_eq!(Sshfs::parse_nlink(b"ls: cannot access"), None);
+    }
+}
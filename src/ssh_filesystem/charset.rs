@@ -0,0 +1,299 @@
+//! ファイル名の文字コード変換モジュール
+//! リモートのファイル名の文字コード(Shift_JIS等)と、ローカルのUTF-8とを相互に変換する。
+//! 変換できない名前は、先頭に"%%"を付け、変換できないバイトのみ"%"と16進2桁で表した名前として見せる。
+//! この形の名前は、ローカルからの指定時に元のバイト列に戻すので、全てのファイルに到達できる。
+//! 16進やUTF-8にすると名前が長くなるので、変換後にNAME_MAXを超える名前はENAMETOOLONGとする。
+
+use super::Error;
+use encoding_rs::{DecoderResult, Encoding};
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// 変換できない名前の先頭に付ける印
+const ESCAPE: &str = "%%";
+
+/// ローカルの名前の長さの上限(バイト数)
+const NAME_MAX: usize = 255;
+
+/// ファイル名の文字コード変換
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct Charset {
+    /// リモートの文字コード。変換しない場合はNone。
+    encoding: Option<&'static Encoding>,
+}
+
+impl Charset {
+    /// リモートの文字コードを指定して生成する。UTF-8の場合は、変換しない。
+    pub(super) fn new(encoding: Option<&'static Encoding>) -> Self {
+        Self {
+            encoding: encoding.filter(|e| *e != encoding_rs::UTF_8),
+        }
+    }
+
+    /// ローカルの名前を、リモートの名前に変換する。
+    /// リモートの文字コードで表せない名前はEILSEQ。
+    /// "%%"で始まる名前は、to_localが返す形のもの以外はEILSEQ。(同じファイルに別名ができないよう)
+    pub(super) fn to_remote<'a>(self, name: &'a OsStr) -> Result<Cow<'a, OsStr>, Error> {
+        let Some(encoding) = self.encoding else {
+            return Ok(Cow::Borrowed(name));
+        };
+        let name = name.to_str().ok_or(Error(libc::EILSEQ))?;
+        if let Some(escaped) = name.strip_prefix(ESCAPE) {
+            let bytes = Self::unescape(encoding, escaped).ok_or(Error(libc::EILSEQ))?;
+            if Self::local_form(encoding, &bytes) != name {
+                return Err(Error(libc::EILSEQ));
+            }
+            return Ok(Cow::Owned(OsString::from_vec(bytes)));
+        }
+        let (bytes, _, unmappable) = encoding.encode(name);
+        if unmappable {
+            return Err(Error(libc::EILSEQ));
+        }
+        Ok(Cow::Owned(OsStr::from_bytes(&bytes).to_os_string()))
+    }
+
+    /// リモートの名前を、ローカルの名前に変換する。
+    /// 変換できない、あるいは変換しても元に戻らない名前と、"%%"で始まる名前は、エスケープした形にする。
+    /// 変換した名前が、NAME_MAXを超える場合はENAMETOOLONG。
+    pub(super) fn to_local<'a>(self, name: &'a OsStr) -> Result<Cow<'a, OsStr>, Error> {
+        let Some(encoding) = self.encoding else {
+            return Ok(Cow::Borrowed(name));
+        };
+        let local = Self::local_form(encoding, name.as_bytes());
+        if local.len() > NAME_MAX {
+            return Err(Error(libc::ENAMETOOLONG));
+        }
+        Ok(Cow::Owned(OsString::from(local)))
+    }
+
+    /// ローカルのパス(シンボリックリンクのリンク先)を、要素毎にリモートの名前に変換する。
+    pub(super) fn path_to_remote<'a>(self, path: &'a Path) -> Result<Cow<'a, Path>, Error> {
+        if self.encoding.is_none() {
+            return Ok(Cow::Borrowed(path));
+        }
+        let mut remote = Vec::new();
+        for (i, name) in path
+            .as_os_str()
+            .as_bytes()
+            .split(|b| *b == b'/')
+            .enumerate()
+        {
+            if i > 0 {
+                remote.push(b'/');
+            }
+            remote.extend_from_slice(self.to_remote(OsStr::from_bytes(name))?.as_bytes());
+        }
+        Ok(Cow::Owned(PathBuf::from(OsString::from_vec(remote))))
+    }
+
+    /// リモートのパス(シンボリックリンクのリンク先)を、要素毎にローカルの名前に変換する。
+    pub(super) fn path_to_local<'a>(self, path: &'a Path) -> Result<Cow<'a, Path>, Error> {
+        if self.encoding.is_none() {
+            return Ok(Cow::Borrowed(path));
+        }
+        let mut local = Vec::new();
+        for (i, name) in path
+            .as_os_str()
+            .as_bytes()
+            .split(|b| *b == b'/')
+            .enumerate()
+        {
+            if i > 0 {
+                local.push(b'/');
+            }
+            local.extend_from_slice(self.to_local(OsStr::from_bytes(name))?.as_bytes());
+        }
+        Ok(Cow::Owned(PathBuf::from(OsString::from_vec(local))))
+    }
+
+    /// リモートの名前のバイト列を、ローカルの名前にする。(長さは確認しない)
+    fn local_form(encoding: &'static Encoding, bytes: &[u8]) -> String {
+        let decoded = encoding
+            .decode_without_bom_handling_and_without_replacement(bytes)
+            .filter(|d| !d.starts_with(ESCAPE))
+            .filter(|d| encoding.encode(d).0 == bytes);
+        if let Some(d) = decoded {
+            return d.into_owned();
+        }
+        // 変換しても元に戻らない文字を含む場合は、全てのバイトを16進で表す。
+        let escaped = Self::escape(encoding, bytes);
+        if Self::unescape(encoding, &escaped[ESCAPE.len()..]).as_deref() == Some(bytes) {
+            return escaped;
+        }
+        let mut hex = String::from(ESCAPE);
+        for b in bytes {
+            hex.push_str(&format!("%{b:02x}"));
+        }
+        hex
+    }
+
+    /// 先頭に"%%"を付け、変換できないバイトと'%'を、"%"と16進2桁で表す。
+    fn escape(encoding: &'static Encoding, bytes: &[u8]) -> String {
+        let mut escaped = String::from(ESCAPE);
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            // 不正なバイトの後は、新しいデコーダーで、その直後から読み直す。
+            let mut decoder = encoding.new_decoder_without_bom_handling();
+            let mut text = String::with_capacity(rest.len() * 3 + 16);
+            let (result, read) =
+                decoder.decode_to_string_without_replacement(rest, &mut text, true);
+            escaped.push_str(&text.replace('%', "%25"));
+            match result {
+                DecoderResult::Malformed(len, after) => {
+                    let end = read - after as usize;
+                    for b in &rest[end - len as usize..end] {
+                        escaped.push_str(&format!("%{b:02x}"));
+                    }
+                    rest = &rest[end..];
+                }
+                _ => break,
+            }
+        }
+        escaped
+    }
+
+    /// escapeの結果("%%"を除いたもの)を、元のバイト列に戻す。
+    fn unescape(encoding: &'static Encoding, escaped: &str) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut parts = escaped.split('%');
+        let push_text = |bytes: &mut Vec<u8>, text: &str| {
+            let (encoded, _, unmappable) = encoding.encode(text);
+            (!unmappable).then(|| bytes.extend_from_slice(&encoded))
+        };
+        push_text(&mut bytes, parts.next()?)?;
+        for part in parts {
+            let hex = part.get(..2)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            push_text(&mut bytes, &part[2..])?;
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sjis() -> Charset {
+        Charset::new(Encoding::for_label(b"sjis"))
+    }
+
+    #[test]
+    fn to_local_test() {
+        let c = sjis();
+        // "日本語.txt"
+        let remote = OsStr::from_bytes(b"\x93\xfa\x96\x7b\x8c\xea.txt");
+        assert_eq!(c.to_local(remote).unwrap(), OsStr::new("日本語.txt"));
+        assert_eq!(c.to_local(OsStr::new("abc")).unwrap(), OsStr::new("abc"));
+        // Shift_JISとして不正なものは、不正なバイトのみ16進にする
+        let broken = OsStr::from_bytes(b"a\x93");
+        assert_eq!(c.to_local(broken).unwrap(), OsStr::new("%%a%93"));
+        // "日本\xff%.txt"
+        let broken = OsStr::from_bytes(b"\x93\xfa\x96\x7b\xff%.txt");
+        assert_eq!(c.to_local(broken).unwrap(), OsStr::new("%%日本%ff%25.txt"));
+        // "%%"で始まるものは、区別のためエスケープする
+        assert_eq!(
+            c.to_local(OsStr::new("%%a")).unwrap(),
+            OsStr::new("%%%25%25a")
+        );
+    }
+
+    #[test]
+    fn escape_round_trip_test() {
+        let c = sjis();
+        for bytes in [
+            b"a\x93".as_slice(),
+            b"\x93",
+            b"\xff\xfe%",
+            b"\x93\xfa\x96\x7b\xff%.txt",
+            b"%%",
+            b"%%a",
+            b"\x81\x40\x81",
+        ] {
+            let remote = OsStr::from_bytes(bytes);
+            let local = c.to_local(remote).unwrap();
+            assert_eq!(c.to_remote(&local).unwrap(), remote, "{local:?}");
+        }
+    }
+
+    #[test]
+    fn name_too_long_test() {
+        let c = sjis();
+        // 変換できないバイトは3バイトになるので、84バイトまでは255バイトに収まる
+        let mut name = b"\xff".repeat(84);
+        assert_eq!(c.to_local(OsStr::from_bytes(&name)).unwrap().len(), 254);
+        name.push(b'a');
+        assert_eq!(c.to_local(OsStr::from_bytes(&name)).unwrap().len(), 255);
+        name.push(b'a');
+        assert_eq!(
+            c.to_local(OsStr::from_bytes(&name)).map_err(|e| e.0),
+            Err(libc::ENAMETOOLONG)
+        );
+        let path = [b"/a/".as_slice(), &name].concat();
+        assert_eq!(
+            c.path_to_local(Path::new(OsStr::from_bytes(&path)))
+                .map_err(|e| e.0),
+            Err(libc::ENAMETOOLONG)
+        );
+        // UTF-8にして長くなる場合も、同じ
+        let long = "あ".repeat(85);
+        let remote = c.to_remote(OsStr::new(&long)).unwrap();
+        assert_eq!(c.to_local(&remote).unwrap(), OsStr::new(&long));
+        let long = "あ".repeat(86);
+        let remote = c.to_remote(OsStr::new(&long)).unwrap();
+        assert_eq!(
+            c.to_local(&remote).map_err(|e| e.0),
+            Err(libc::ENAMETOOLONG)
+        );
+    }
+
+    #[test]
+    fn to_remote_test() {
+        let c = sjis();
+        assert_eq!(
+            c.to_remote(OsStr::new("日本語.txt")).unwrap(),
+            OsStr::from_bytes(b"\x93\xfa\x96\x7b\x8c\xea.txt")
+        );
+        assert_eq!(
+            c.to_remote(OsStr::new("%%a%93")).unwrap(),
+            OsStr::from_bytes(b"a\x93")
+        );
+        let err = |name: &str| c.to_remote(OsStr::new(name)).map_err(|e| e.0).err();
+        // Shift_JISで表せない文字
+        assert_eq!(err("한국어"), Some(libc::EILSEQ));
+        assert_eq!(err("%%a%zz"), Some(libc::EILSEQ));
+        assert_eq!(err("%%a%9"), Some(libc::EILSEQ));
+        assert_eq!(err("%%"), Some(libc::EILSEQ));
+        // to_localが返さない形(エスケープ不要、大文字の16進)は、別名になるので受け付けない
+        assert_eq!(err("%%abc"), Some(libc::EILSEQ));
+        assert_eq!(err("%%a%61"), Some(libc::EILSEQ));
+        assert_eq!(err("%%a%9F"), Some(libc::EILSEQ));
+
+        // 変換しない場合は、そのまま
+        let none = Charset::new(Encoding::for_label(b"utf-8"));
+        let bytes = OsStr::from_bytes(b"a\x93");
+        assert_eq!(none.to_remote(bytes).unwrap(), bytes);
+        assert_eq!(none.to_local(bytes).unwrap(), bytes);
+    }
+
+    #[test]
+    fn path_test() {
+        let c = sjis();
+        let local = Path::new("../日本/a//b");
+        let remote = c.path_to_remote(local).unwrap();
+        assert_eq!(
+            remote.as_os_str().as_bytes(),
+            b"../\x93\xfa\x96\x7b/a//b".as_slice()
+        );
+        assert_eq!(c.path_to_local(&remote).unwrap(), local);
+        assert_eq!(
+            c.path_to_remote(Path::new("/abs/")).unwrap(),
+            Path::new("/abs/")
+        );
+    }
+}
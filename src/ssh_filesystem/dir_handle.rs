@@ -0,0 +1,215 @@
+//! ディレクトリハンドル管理モジュール
+
+use super::remote_stat::RemoteStat;
+use ssh2::{ErrorCode, FileStat};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// libssh2のエラーコード LIBSSH2_ERROR_FILE (ssh2::File::readdirでは、エントリの終端を示す)
+const LIBSSH2_ERROR_FILE: i32 = -16;
+
+/// エントリを一つずつ読み出せる、ディレクトリハンドル
+pub(super) trait ReadDir {
+    /// 次のエントリを返す。終端では、LIBSSH2_ERROR_FILEのエラーを返す。
+    fn readdir(&mut self) -> Result<(PathBuf, FileStat), ssh2::Error>;
+}
+
+impl ReadDir for ssh2::File {
+    fn readdir(&mut self) -> Result<(PathBuf, FileStat), ssh2::Error> {
+        ssh2::File::readdir(self)
+    }
+}
+
+/// オープン中のディレクトリ
+/// リモートのディレクトリハンドルから必要な分だけエントリを読み進め、読んだものはバッファに保持する。
+/// 同じハンドルに対しては、オフセットが同じなら常に同じエントリを返す。
+pub(super) struct DirStream<D: ReadDir = ssh2::File> {
+    dir: D,
+    entries: Vec<(OsString, FileStat)>,
+    eof: bool,
+    /// リモートのinode番号から求めた、エントリのinode番号(--stable-inode時のみ)
+    remote_inos: Option<HashMap<OsString, u64>>,
+    /// リモートのstatで取得した、エントリの詳細な属性(--rich-stat時のみ)
+    remote_stats: Option<HashMap<OsString, RemoteStat>>,
+}
+
+impl<D: ReadDir> DirStream<D> {
+    pub(super) fn new(dir: D) -> Self {
+        Self {
+            dir,
+            entries: Vec::new(),
+            eof: false,
+            remote_inos: None,
+            remote_stats: None,
+        }
+    }
+
+    /// index番目のエントリを返す。("." ".."は含まない)
+    /// バッファにまだなければ、リモートから読み進める。エントリが尽きた場合はNoneを返す。
+    pub(super) fn get(
+        &mut self,
+        index: usize,
+    ) -> Result<Option<&(OsString, FileStat)>, ssh2::Error> {
+        while self.entries.len() <= index && !self.eof {
+            match self.dir.readdir() {
+                Ok((name, stat)) => {
+                    if name == Path::new(".") || name == Path::new("..") {
+                        continue;
+                    }
+                    self.entries.push((name.into_os_string(), stat));
+                }
+                Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_FILE) => self.eof = true,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.entries.get(index))
+    }
+
+    /// エントリのinode番号が設定済みかどうか。
+    pub(super) fn has_remote_inos(&self) -> bool {
+        self.remote_inos.is_some()
+    }
+
+    /// エントリのinode番号を設定する。
+    pub(super) fn set_remote_inos(&mut self, inos: HashMap<OsString, u64>) {
+        self.remote_inos = Some(inos);
+    }
+
+    /// nameのエントリのinode番号を返す。
+    pub(super) fn remote_ino(&self, name: &OsStr) -> Option<u64> {
+        self.remote_inos.as_ref()?.get(name).copied()
+    }
+
+    /// エントリの詳細な属性が設定済みかどうか。
+    pub(super) fn has_remote_stats(&self) -> bool {
+        self.remote_stats.is_some()
+    }
+
+    /// エントリの詳細な属性を設定する。
+    pub(super) fn set_remote_stats(&mut self, stats: HashMap<OsString, RemoteStat>) {
+        self.remote_stats = Some(stats);
+    }
+
+    /// nameのエントリの詳細な属性を返す。
+    pub(super) fn remote_stat(&self, name: &OsStr) -> Option<&RemoteStat> {
+        self.remote_stats.as_ref()?.get(name)
+    }
+}
+
+/// ディレクトリハンドル管理構造体
+pub(super) struct Dhandles {
+    list: Mutex<HashMap<u64, Arc<Mutex<DirStream>>>>,
+    next_handle: AtomicU64,
+}
+
+impl Dhandles {
+    pub(super) fn new() -> Self {
+        Self {
+            list: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn add_dir(&mut self, dir: ssh2::File) -> u64 {
+        let handle = self.next_handle.fetch_add(1, Ordering::AcqRel);
+        self.list
+            .lock()
+            .unwrap()
+            .insert(handle, Arc::new(Mutex::new(DirStream::new(dir))));
+        handle
+        // 注釈:Fhandlesと同様、リストの毒化時は即座にシステムを落とす。
+        // よって、このモジュール内において、lock().unwrap()とする。
+    }
+
+    pub(super) fn get_dir(&self, fh: u64) -> Option<Arc<Mutex<DirStream>>> {
+        self.list.lock().unwrap().get(&fh).cloned()
+    }
+
+    pub(super) fn del_dir(&mut self, fh: u64) {
+        self.list.lock().unwrap().remove(&fh); // バッファと共に、リモートのハンドルもクローズされる。
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::*;
+
+    /// テスト用の、エントリのリストから読み出すディレクトリハンドル
+    pub(in crate::ssh_filesystem) struct FakeDir {
+        entries: std::vec::IntoIter<(PathBuf, FileStat)>,
+        /// readdirの呼び出し回数
+        pub(in crate::ssh_filesystem) reads: usize,
+    }
+
+    impl FakeDir {
+        pub(in crate::ssh_filesystem) fn new(names: &[&str]) -> Self {
+            let entries = names
+                .iter()
+                .map(|n| (PathBuf::from(n), stat(0o100644, 1)))
+                .collect::<Vec<_>>();
+            Self {
+                entries: entries.into_iter(),
+                reads: 0,
+            }
+        }
+    }
+
+    impl ReadDir for FakeDir {
+        fn readdir(&mut self) -> Result<(PathBuf, FileStat), ssh2::Error> {
+            self.reads += 1;
+            self.entries.next().ok_or(ssh2::Error::new(
+                ErrorCode::Session(LIBSSH2_ERROR_FILE),
+                "end of directory",
+            ))
+        }
+    }
+
+    pub(in crate::ssh_filesystem) fn stat(perm: u32, size: u64) -> FileStat {
+        FileStat {
+            size: Some(size),
+            uid: Some(1000),
+            gid: Some(1000),
+            perm: Some(perm),
+            atime: Some(1_700_000_000),
+            mtime: Some(1_700_000_001),
+        }
+    }
+
+    #[test]
+    fn dir_stream_test() {
+        let mut dir = DirStream::new(FakeDir::new(&[".", "a", "..", "b", "c"]));
+        // 必要な分だけ読み進め、"." ".."は飛ばす
+        assert_eq!(dir.get(0).unwrap().unwrap().0, "a");
+        assert_eq!(dir.dir.reads, 2);
+        assert_eq!(dir.get(1).unwrap().unwrap().0, "b");
+        assert_eq!(dir.dir.reads, 4);
+        // 読んだものは、バッファから返す
+        assert_eq!(dir.get(0).unwrap().unwrap().0, "a");
+        assert_eq!(dir.dir.reads, 4);
+        assert_eq!(dir.get(2).unwrap().unwrap().0, "c");
+        // 終端の後は、リモートを読まない
+        assert!(dir.get(3).unwrap().is_none());
+        assert_eq!(dir.dir.reads, 6);
+        assert!(dir.get(10).unwrap().is_none());
+        assert_eq!(dir.dir.reads, 6);
+        assert_eq!(dir.get(1).unwrap().unwrap().0, "b");
+    }
+
+    #[test]
+    fn dir_stream_error_test() {
+        struct BrokenDir;
+        impl ReadDir for BrokenDir {
+            fn readdir(&mut self) -> Result<(PathBuf, FileStat), ssh2::Error> {
+                Err(ssh2::Error::new(ErrorCode::Session(-7), "socket send"))
+            }
+        }
+        let mut dir = DirStream::new(BrokenDir);
+        let e = dir.get(0).unwrap_err();
+        assert_eq!(e.code(), ErrorCode::Session(-7));
+    }
+}
@@ -0,0 +1,295 @@
+//! uid/gid対応付けモジュール
+//! リモートのファイルの所有者(uid/gid)を、ローカル側でどう見せるかを管理する。
+
+use super::{remote_cmd, remote_user::RemoteUser, Error};
+use crate::cmdline_opt::{IdMap, Opt};
+use anyhow::{anyhow, Context};
+use log::warn;
+use ssh2::{FileStat, Session};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// uid/gidの対応表
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct IdMapper {
+    /// trueなら、全てのファイルを要求元のユーザーの所有として見せる。(--idmap未指定時)
+    requester: bool,
+    /// リモートのuid -> ローカルのuid
+    uids: HashMap<u32, u32>,
+    /// リモートのgid -> ローカルのgid
+    gids: HashMap<u32, u32>,
+}
+
+impl IdMapper {
+    /// コマンドラインオプションから生成する。
+    /// "user"で、リモートユーザーの情報が取れていなければ、対応付けなし(none)とする。
+    /// "name"で、リモートのユーザー・グループの一覧が取れなければ、同じく対応付けなしとする。
+    pub(super) fn from_opt(
+        opt: &Opt,
+        session: &Session,
+        remote_user: Option<&RemoteUser>,
+    ) -> anyhow::Result<Self> {
+        let mut map = Self::default();
+        match opt.idmap {
+            None => map.requester = true,
+            Some(IdMap::None) => {}
+            Some(IdMap::User) => match remote_user {
+                Some(user) => {
+                    map.uids.insert(user.uid, users::get_current_uid());
+                    map.gids.insert(user.gid, users::get_current_gid());
+                }
+                None => warn!("Remote user id is unknown. --idmap user is treated as none."),
+            },
+            Some(IdMap::File) => {
+                if opt.uidfile.is_none() && opt.gidfile.is_none() {
+                    return Err(anyhow!("--idmap file requires --uidfile or --gidfile."));
+                }
+                if let Some(file) = &opt.uidfile {
+                    map.uids = Self::load_file(file, |name| {
+                        users::get_user_by_name(name).map(|u| u.uid())
+                    })?;
+                }
+                if let Some(file) = &opt.gidfile {
+                    map.gids = Self::load_file(file, |name| {
+                        users::get_group_by_name(name).map(|g| g.gid())
+                    })?;
+                }
+            }
+            Some(IdMap::Name) => {
+                let remote = Self::fetch_remote_db(session, "passwd")
+                    .and_then(|users| Ok((users, Self::fetch_remote_db(session, "group")?)));
+                match remote {
+                    Ok((remote_users, remote_groups)) => {
+                        map.uids = Self::map_by_name(remote_users, |name| {
+                            users::get_user_by_name(name).map(|u| u.uid())
+                        });
+                        map.gids = Self::map_by_name(remote_groups, |name| {
+                            users::get_group_by_name(name).map(|g| g.gid())
+                        });
+                    }
+                    Err(e) => warn!(
+                        "Failed to get remote users and groups. --idmap name is treated as none. -- {:?}",
+                        e
+                    ),
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// ファイルの属性から、ローカル側で見せるuidとgidを求める。
+    /// 対応表にないidは、リモートの値をそのまま使う。
+    pub(super) fn local_ids(&self, stat: &FileStat, req_uid: u32, req_gid: u32) -> (u32, u32) {
+        if self.requester {
+            return (req_uid, req_gid);
+        }
+        let uid = stat
+            .uid
+            .map_or(req_uid, |u| *self.uids.get(&u).unwrap_or(&u));
+        let gid = stat
+            .gid
+            .map_or(req_gid, |g| *self.gids.get(&g).unwrap_or(&g));
+        (uid, gid)
+    }
+
+    /// ローカルのuidから、リモートのuidを求める。対応付けられないものはNone。
+    pub(super) fn remote_uid(&self, local: u32) -> Option<u32> {
+        self.reverse(&self.uids, local)
+    }
+
+    /// ローカルのgidから、リモートのgidを求める。対応付けられないものはNone。
+    pub(super) fn remote_gid(&self, local: u32) -> Option<u32> {
+        self.reverse(&self.gids, local)
+    }
+
+    /// local_idsの逆変換。
+    /// 対応表にあればその値。なければ同じ値とするが、その値のリモートのidが別のidとして
+    /// 見えている場合は、対応付けられない。全ファイルを要求元の所有として見せる場合も同様。
+    fn reverse(&self, map: &HashMap<u32, u32>, local: u32) -> Option<u32> {
+        if self.requester {
+            return None;
+        }
+        let remote = map
+            .iter()
+            .filter(|(_, l)| **l == local)
+            .map(|(r, _)| *r)
+            .min();
+        match remote {
+            Some(r) => Some(r),
+            None if map.contains_key(&local) => None,
+            None => Some(local),
+        }
+    }
+
+    /// リモートのユーザー(passwd)またはグループ(group)の一覧を、(名前, id)のリストで取得する。
+    fn fetch_remote_db(session: &Session, db: &str) -> Result<Vec<(String, u32)>, Error> {
+        let command = format!("getent {db} || cat /etc/{db}");
+        let out = remote_cmd::exec(session, &command)?.into_result()?;
+        Ok(Self::parse_db(&String::from_utf8_lossy(&out)))
+    }
+
+    /// passwd/group形式("名前:パスワード:id:...")の一覧を解析する。解析できない行は無視する。
+    fn parse_db(text: &str) -> Vec<(String, u32)> {
+        text.lines()
+            .filter_map(|line| {
+                let mut fields = line.split(':');
+                let name = fields.next().filter(|n| !n.is_empty())?;
+                let id = fields.nth(1)?.parse().ok()?;
+                Some((name.to_string(), id))
+            })
+            .collect()
+    }
+
+    /// リモートの(名前, id)のリストから、同じ名前のローカルのidへの対応表を作る。
+    /// ローカルに同じ名前がないものは、対応表に含めない。
+    fn map_by_name<F>(remote: Vec<(String, u32)>, resolve: F) -> HashMap<u32, u32>
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        remote
+            .into_iter()
+            .filter_map(|(name, id)| Some((id, resolve(&name)?)))
+            .collect()
+    }
+
+    /// 対応ファイルを読み込み、リモートのid -> ローカルのidの表を作る。
+    fn load_file<F>(path: &Path, resolve: F) -> anyhow::Result<HashMap<u32, u32>>
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read id mapping file {:?}.", path))?;
+        Self::parse_file(&text, resolve).with_context(|| format!("In id mapping file {:?}.", path))
+    }
+
+    /// 対応ファイルを解析する。
+    /// 各行は"ローカルの名前:リモートのid"。ローカル側は、数値のidでもよい。
+    /// 空行と'#'から始まる行は無視する。
+    fn parse_file<F>(text: &str, resolve: F) -> anyhow::Result<HashMap<u32, u32>>
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        let mut map = HashMap::new();
+        for (no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((local, remote)) = line.split_once(':') else {
+                return Err(anyhow!("line {}: expected \"name:id\".", no + 1));
+            };
+            let remote = remote
+                .trim()
+                .parse::<u32>()
+                .with_context(|| format!("line {}: invalid remote id.", no + 1))?;
+            let local = local.trim();
+            let local = local
+                .parse::<u32>()
+                .ok()
+                .or_else(|| resolve(local))
+                .ok_or_else(|| anyhow!("line {}: unknown local name \"{}\".", no + 1, local))?;
+            map.insert(remote, local);
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stat(uid: u32, gid: u32) -> FileStat {
+        FileStat {
+            size: None,
+            uid: Some(uid),
+            gid: Some(gid),
+            perm: None,
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    #[test]
+    fn parse_file_test() {
+        let resolve = |name: &str| match name {
+            "mito" => Some(1000),
+            "guest" => Some(1001),
+            _ => None,
+        };
+        let map = IdMapper::parse_file("# comment\nmito:500\n\n guest : 501 \n1002:502\n", resolve)
+            .unwrap();
+        assert_eq!(map, HashMap::from([(500, 1000), (501, 1001), (502, 1002)]));
+        assert!(IdMapper::parse_file("mito\n", resolve).is_err());
+        assert!(IdMapper::parse_file("mito:abc\n", resolve).is_err());
+        assert!(IdMapper::parse_file("nobody_here:500\n", resolve).is_err());
+    }
+
+    #[test]
+    fn map_by_name_test() {
+        let remote = IdMapper::parse_db(
+            "root:x:0:0:root:/root:/bin/bash\n\
+             www-data:x:33:33:www-data:/var/www:/usr/sbin/nologin\n\
+             alice:x:1001:1001::/home/alice:/bin/sh\n\
+             broken line\n",
+        );
+        assert_eq!(
+            remote,
+            vec![
+                ("root".to_string(), 0),
+                ("www-data".to_string(), 33),
+                ("alice".to_string(), 1001)
+            ]
+        );
+        let local = |name: &str| match name {
+            "root" => Some(0),
+            "www-data" => Some(82),
+            _ => None,
+        };
+        assert_eq!(
+            IdMapper::map_by_name(remote, local),
+            HashMap::from([(0, 0), (33, 82)])
+        );
+        assert_eq!(
+            IdMapper::parse_db("wheel:x:10:mito,guest\n"),
+            vec![("wheel".to_string(), 10)]
+        );
+    }
+
+    #[test]
+    fn local_ids_test() {
+        let requester = IdMapper {
+            requester: true,
+            ..Default::default()
+        };
+        assert_eq!(requester.local_ids(&stat(500, 500), 1000, 100), (1000, 100));
+
+        let map = IdMapper {
+            requester: false,
+            uids: HashMap::from([(500, 1000)]),
+            gids: HashMap::from([(600, 100)]),
+        };
+        assert_eq!(map.local_ids(&stat(500, 600), 0, 0), (1000, 100));
+        assert_eq!(map.local_ids(&stat(0, 20), 1000, 100), (0, 20));
+    }
+
+    #[test]
+    fn reverse_test() {
+        let requester = IdMapper {
+            requester: true,
+            ..Default::default()
+        };
+        assert_eq!(requester.remote_uid(1000), None);
+
+        let map = IdMapper {
+            requester: false,
+            uids: HashMap::from([(500, 1000)]),
+            gids: HashMap::from([(600, 100)]),
+        };
+        assert_eq!(map.remote_uid(1000), Some(500));
+        assert_eq!(map.remote_uid(0), Some(0));
+        // リモートの500は1000として見えるので、ローカルの500には対応付けられない。
+        assert_eq!(map.remote_uid(500), None);
+        assert_eq!(map.remote_gid(100), Some(600));
+        assert_eq!(map.remote_gid(600), None);
+    }
+}
@@ -1,147 +1,647 @@
 //! Inode管理モジュール
+//! inodeは、フルパスではなく(親ディレクトリのinode, 名前)の組で管理する。
+//! ディレクトリの名前変更・削除は、その配下のinodeにそのまま反映される。
+//! ハードリンクは、同じinodeを指す別名として、主の名前とは別に管理する。
 
 use super::bi_hash_map::BiHashMap;
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Mutex,
 };
 
+/// ルートディレクトリのinode番号
+pub(super) const ROOT_INODE: u64 = fuser::FUSE_ROOT_ID;
+
+/// 参照数が0になっても保持するinodeの数の上限
+const FORGOTTEN_MAX: usize = 65536;
+
 /// Inode管理構造体
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub(super) struct Inodes {
-    list: Mutex<BiHashMap<u64, PathBuf>>,
+    /// inode <-> (親inode, 名前)
+    list: Mutex<BiHashMap<u64, (u64, OsString)>>,
+    /// ハードリンクの別名 (親inode, 名前) -> inode。主の名前は、listに登録する。
+    aliases: Mutex<HashMap<(u64, OsString), u64>>,
+    /// 親inode -> 登録されている子のinode
+    children: Mutex<HashMap<u64, HashSet<u64>>>,
+    /// inode -> カーネルからの参照数(lookup count)
+    lookups: Mutex<HashMap<u64, u64>>,
+    /// ルートディレクトリのリモート側のパス
+    root: PathBuf,
     next_inode: AtomicU64,
+    /// trueなら、参照数が0になっても登録を削除しない。(NFSエクスポート用)
+    keep_forgotten: bool,
+    /// keep_forgotten時、保持するinodeの数の上限
+    forgotten_max: usize,
+    /// 参照数が0になって保持しているinode -> 保持した順番
+    forgotten: HashMap<u64, u64>,
+    /// 保持した順番 -> inode (古いものから削除する)
+    forgotten_order: BTreeMap<u64, u64>,
+    forgotten_seq: u64,
 }
 
 impl Inodes {
     /// Inodesを生成する
-    pub(super) fn new() -> Self {
+    /// rootは、ファイルシステムのルート(inode番号 ROOT_INODE)に対応するリモートのパス。
+    pub(super) fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self::with_first_inode(root, ROOT_INODE + 1)
+    }
+
+    /// 連番で割り当てるinode番号の開始値を指定して、Inodesを生成する
+    pub(super) fn with_first_inode<P: AsRef<Path>>(root: P, first: u64) -> Self {
         Self {
             list: Mutex::new(BiHashMap::new()),
-            next_inode: AtomicU64::new(2),
+            aliases: Mutex::new(HashMap::new()),
+            children: Mutex::new(HashMap::new()),
+            lookups: Mutex::new(HashMap::new()),
+            root: root.as_ref().into(),
+            next_inode: AtomicU64::new(first),
+            keep_forgotten: false,
+            forgotten_max: FORGOTTEN_MAX,
+            forgotten: HashMap::new(),
+            forgotten_order: BTreeMap::new(),
+            forgotten_seq: 0,
         }
     }
 
-    /// pathで指定されたinodeを生成し、登録する。
-    /// すでにpathの登録が存在する場合、追加はせず、登録済みのinodeを返す。
-    /// 初めて、この関数が呼ばれるときは、ファイルシステムにおけるルートであり、inode番号2が割り当てられる。
-    pub(super) fn add<P: AsRef<Path>>(&mut self, path: P) -> u64 {
+    /// 参照数が0になったinodeの登録を保持するかどうかを設定する。
+    /// NFSエクスポート時は、カーネルがforgetした後も、ファイルハンドルからinodeを引けるよう保持する。
+    /// 保持する数には上限があり、超えたら古いものから削除する。
+    /// (--stable-inode時は、削除したinodeも、リモートのinode番号から登録し直せる)
+    pub(super) fn set_keep_forgotten(&mut self, keep: bool) {
+        self.keep_forgotten = keep;
+    }
+
+    /// 親ディレクトリのinodeと名前で指定されたinodeを生成し、登録する。
+    /// すでに登録が存在する場合、追加はせず、登録済みのinodeを返す。
+    pub(super) fn add(&mut self, parent: u64, name: &OsStr) -> u64 {
         let mut list_guard = self.list.lock().unwrap();
         // 注釈:このリストが毒化されたら、もはや、全システムにわたり、inode管理の正当性を保証できない。
         // 最善の方法が、即時システムを落とすことである。
         // 以下、このモジュール全体に共通。
-        let path = PathBuf::from(path.as_ref());
-        match list_guard.get_left(&path) {
-            Some(i) => *i,
-            None => {
-                let inode = self.next_inode.fetch_add(1, Ordering::AcqRel);
-                if list_guard.insert_no_overwrite(inode, path.clone()).is_err() {
-                    unreachable!("Unexpected duplicate inode {} or path {:?}", inode, path);
-                    // 既に重複がチェックされているので、ありえない。
+        let key = (parent, name.to_os_string());
+        if let Some(i) = list_guard.get_left(&key) {
+            return *i;
+        }
+        if let Some(i) = self.aliases.lock().unwrap().get(&key) {
+            return *i;
+        }
+        let inode = self.next_inode.fetch_add(1, Ordering::AcqRel);
+        if list_guard.insert_no_overwrite(inode, key).is_err() {
+            unreachable!("Unexpected duplicate inode {} or name {:?}", inode, name);
+            // 既に重複がチェックされているので、ありえない。
+        }
+        self.children
+            .lock()
+            .unwrap()
+            .entry(parent)
+            .or_default()
+            .insert(inode);
+        inode
+    }
+
+    /// 親ディレクトリのinodeと名前で指定されたエントリを、指定のinode番号で登録する。
+    /// 名前が別のinodeで登録済みなら(リモートで置き換えられた)、古い登録を配下ごと削除する。
+    /// inode番号が別の名前で使用中なら、ハードリンクとして、その別名で登録する。
+    /// 登録したinode番号を返す。
+    pub(super) fn add_with_ino(&mut self, parent: u64, name: &OsStr, inode: u64) -> u64 {
+        match self.get_inode(parent, name) {
+            Some(i) if i == inode => return inode,
+            Some(_) => {
+                self.remove(parent, name);
+            }
+            None => {}
+        }
+        if inode == ROOT_INODE {
+            return self.add(parent, name);
+        }
+        if self.list.lock().unwrap().contains_left(&inode) {
+            return self.add_alias(parent, name, inode);
+        }
+        self.list
+            .lock()
+            .unwrap()
+            .insert(inode, (parent, name.to_os_string()));
+        self.children
+            .lock()
+            .unwrap()
+            .entry(parent)
+            .or_default()
+            .insert(inode);
+        inode
+    }
+
+    /// 親ディレクトリのinodeと名前で指定されたinodeを登録し、カーネルからの参照数を1増やす。
+    /// カーネルにエントリとして返すinodeは、この関数で登録する。
+    pub(super) fn lookup(&mut self, parent: u64, name: &OsStr) -> u64 {
+        let inode = self.add(parent, name);
+        self.inc_lookup(inode)
+    }
+
+    /// lookupと同じ。ただし、未登録であれば、指定のinode番号で登録する。(add_with_ino参照)
+    pub(super) fn lookup_with_ino(&mut self, parent: u64, name: &OsStr, inode: u64) -> u64 {
+        let inode = self.add_with_ino(parent, name, inode);
+        self.inc_lookup(inode)
+    }
+
+    /// 親ディレクトリのinodeと名前を、登録済みのinodeのハードリンクとして登録し、
+    /// カーネルからの参照数を1増やす。inodeが登録されていなければ、add_aliasと同じく連番で登録する。
+    pub(super) fn lookup_alias(&mut self, parent: u64, name: &OsStr, inode: u64) -> u64 {
+        let inode = self.add_alias(parent, name, inode);
+        self.inc_lookup(inode)
+    }
+
+    /// 親ディレクトリのinodeと名前を、登録済みのinodeの別名(ハードリンク)として登録する。
+    /// 名前が別のinodeで登録済みなら、古い登録を削除する。
+    /// inodeが登録されていなければ、名前を連番のinodeで登録する。登録したinode番号を返す。
+    fn add_alias(&mut self, parent: u64, name: &OsStr, inode: u64) -> u64 {
+        match self.get_inode(parent, name) {
+            Some(i) if i == inode => return inode,
+            Some(_) => {
+                self.remove(parent, name);
+            }
+            None => {}
+        }
+        if inode == ROOT_INODE || !self.list.lock().unwrap().contains_left(&inode) {
+            return self.add(parent, name);
+        }
+        self.aliases
+            .lock()
+            .unwrap()
+            .insert((parent, name.to_os_string()), inode);
+        inode
+    }
+
+    /// 登録済みのinodeの参照数を1増やす。("." ".."のlookup用)
+    /// 登録されていなければfalseを返す。
+    pub(super) fn lookup_inode(&mut self, inode: u64) -> bool {
+        if inode != ROOT_INODE && !self.list.lock().unwrap().contains_left(&inode) {
+            return false;
+        }
+        self.inc_lookup(inode);
+        true
+    }
+
+    /// カーネルからの参照数を1増やす。
+    fn inc_lookup(&mut self, inode: u64) -> u64 {
+        *self.lookups.lock().unwrap().entry(inode).or_insert(0) += 1;
+        if let Some(seq) = self.forgotten.remove(&inode) {
+            self.forgotten_order.remove(&seq);
+        }
+        inode
+    }
+
+    /// カーネルからの参照数をnlookup減らす。
+    /// 参照数が0になり、配下に登録されたinodeもなくなったものは、登録を削除する。
+    pub(super) fn forget(&mut self, inode: u64, nlookup: u64) {
+        {
+            let mut lookups = self.lookups.lock().unwrap();
+            let Some(count) = lookups.get_mut(&inode) else {
+                return;
+            };
+            *count = count.saturating_sub(nlookup);
+            if *count != 0 {
+                return;
+            }
+            lookups.remove(&inode);
+        }
+        if self.keep_forgotten {
+            self.keep(inode);
+        } else {
+            self.release(inode);
+        }
+    }
+
+    /// 参照数が0になったinodeを保持する。上限を超えたら、古いものから登録を削除する。
+    /// 削除済みのinodeが残っていることもあるが、releaseは何もしないので、そのまま数える。
+    fn keep(&mut self, inode: u64) {
+        let seq = self.forgotten_seq;
+        self.forgotten_seq += 1;
+        if let Some(old) = self.forgotten.insert(inode, seq) {
+            self.forgotten_order.remove(&old);
+        }
+        self.forgotten_order.insert(seq, inode);
+        while self.forgotten.len() > self.forgotten_max {
+            let Some((_, oldest)) = self.forgotten_order.pop_first() else {
+                break;
+            };
+            self.forgotten.remove(&oldest);
+            self.release(oldest);
+        }
+    }
+
+    /// 参照がなくなったinodeを、ハードリンクの別名も含めて削除する。
+    /// 配下(別名を含む)が残っている場合は、それがなくなるまで保持する。
+    /// 削除により、参照も配下もなくなった親ディレクトリも、続けて削除する。
+    fn release(&mut self, inode: u64) {
+        let mut inode = inode;
+        while inode != ROOT_INODE {
+            let has_children = self
+                .children
+                .lock()
+                .unwrap()
+                .get(&inode)
+                .is_some_and(|c| !c.is_empty())
+                || self
+                    .aliases
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .any(|(p, _)| *p == inode);
+            if has_children || self.lookups.lock().unwrap().contains_key(&inode) {
+                return;
+            }
+            let Some((parent, _)) = self.list.lock().unwrap().remove_left(&inode) else {
+                return;
+            };
+            self.detach_from_parent(parent, inode);
+            let mut parents = vec![parent];
+            self.aliases.lock().unwrap().retain(|(p, _), i| {
+                if *i == inode {
+                    parents.push(*p);
                 }
-                inode
+                *i != inode
+            });
+            // 別名の親ディレクトリも、参照がなければ削除できるようになる。
+            for p in parents.into_iter().skip(1) {
+                self.release(p);
             }
+            inode = parent;
+        }
+    }
+
+    /// 親ディレクトリのinodeと名前から、inodeを取得する
+    /// ハードリンクの別名も対象とする。
+    pub(super) fn get_inode(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        let key = (parent, name.to_os_string());
+        if let Some(i) = self.list.lock().unwrap().get_left(&key) {
+            return Some(*i);
         }
+        self.aliases.lock().unwrap().get(&key).copied()
     }
 
-    /// pathからinodeを取得する
-    /// 主用途が消滅したが、将来のために残しておく
-    #[allow(dead_code)]
-    pub(super) fn get_inode<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
-        let path = PathBuf::from(path.as_ref());
-        self.list.lock().unwrap().get_left(&path).copied()
+    /// 親ディレクトリのinodeを取得する。ルートの親はルート自身とする。
+    pub(super) fn get_parent(&self, inode: u64) -> Option<u64> {
+        if inode == ROOT_INODE {
+            return Some(ROOT_INODE);
+        }
+        self.list.lock().unwrap().get_right(&inode).map(|(p, _)| *p)
     }
 
     /// inodeからpathを取得する
+    /// 親をたどってルートまでの名前を連結する。ハードリンクは、主の名前のパスとなる。
     pub(super) fn get_path(&self, inode: u64) -> Option<PathBuf> {
-        self.list.lock().unwrap().get_right(&inode).cloned()
+        let list_guard = self.list.lock().unwrap();
+        let mut names = Vec::new();
+        let mut cur = inode;
+        while cur != ROOT_INODE {
+            let (parent, name) = list_guard.get_right(&cur)?;
+            names.push(name);
+            cur = *parent;
+        }
+        let mut path = self.root.clone();
+        path.extend(names.iter().rev());
+        Some(path)
     }
 
-    /// inodesから、inodeの登録を削除する
-    /// (主用途がなくなっちゃったけど、将来のために残しておく)
-    #[allow(dead_code)]
-    pub(super) fn del_inode(&mut self, inode: u64) -> Option<u64> {
-        self.list.lock().unwrap().remove_left(&inode).map(|_| inode)
+    /// 親ディレクトリのinodeと名前で指定されたinodeの登録を、配下のinodeも含めて削除する。
+    /// ハードリンクの別名が残るinodeは削除せず、別名を主の名前にする。
+    /// 削除したinodeを返す。
+    pub(super) fn remove(&mut self, parent: u64, name: &OsStr) -> Option<u64> {
+        let key = (parent, name.to_os_string());
+        if let Some(inode) = self.aliases.lock().unwrap().remove(&key) {
+            return Some(inode);
+        }
+        let inode = self.list.lock().unwrap().get_left(&key).copied()?;
+        if self.promote_alias(inode) {
+            return Some(inode);
+        }
+        self.list.lock().unwrap().remove_right(&key);
+        self.detach_from_parent(parent, inode);
+        // 削除する配下のディレクトリにある別名を、先に削除しておく。
+        let mut subtree = HashSet::from([inode]);
+        let mut stack = vec![inode];
+        while let Some(i) = stack.pop() {
+            if let Some(children) = self.children.lock().unwrap().get(&i) {
+                subtree.extend(children);
+                stack.extend(children);
+            }
+        }
+        self.aliases
+            .lock()
+            .unwrap()
+            .retain(|(p, _), _| !subtree.contains(p));
+
+        let mut stack = vec![inode];
+        while let Some(i) = stack.pop() {
+            self.lookups.lock().unwrap().remove(&i);
+            let Some(children) = self.children.lock().unwrap().remove(&i) else {
+                continue;
+            };
+            for c in children {
+                // 削除するディレクトリの外に別名があれば、そちらに残す。
+                if !self.promote_alias(c) {
+                    self.list.lock().unwrap().remove_left(&c);
+                    stack.push(c);
+                }
+            }
+        }
+        Some(inode)
     }
 
-    /// path名からiNodeの登録を削除する
-    pub(super) fn del_inode_with_path<P: AsRef<Path>>(&mut self, path: P) -> Option<u64> {
-        let path = PathBuf::from(path.as_ref());
-        self.list.lock().unwrap().remove_right(&path)
+    /// inodeにハードリンクの別名があれば、そのひとつを主の名前にする。別名がなければfalse。
+    fn promote_alias(&mut self, inode: u64) -> bool {
+        let key = {
+            let mut aliases = self.aliases.lock().unwrap();
+            let Some(key) = aliases
+                .iter()
+                .find(|(_, i)| **i == inode)
+                .map(|(k, _)| k.clone())
+            else {
+                return false;
+            };
+            aliases.remove(&key);
+            key
+        };
+        let new_parent = key.0;
+        let old = self.list.lock().unwrap().remove_left(&inode);
+        if let Some((old_parent, _)) = old {
+            self.detach_from_parent(old_parent, inode);
+        }
+        self.list.lock().unwrap().insert(inode, key);
+        self.children
+            .lock()
+            .unwrap()
+            .entry(new_parent)
+            .or_default()
+            .insert(inode);
+        true
     }
 
-    /// 登録されているinodeのpathを変更する。
-    /// old_pathが存在しなければ、なにもしない。
-    pub(super) fn rename<P: AsRef<Path>>(&mut self, old_path: P, new_path: P) {
-        let old_path = PathBuf::from(old_path.as_ref());
-        let new_path = PathBuf::from(new_path.as_ref());
-        let mut list_guard = self.list.lock().unwrap();
-        let Some(ino) = list_guard.get_left(&old_path).copied() else {
+    /// 登録されているinodeの親と名前を変更する。配下のinodeは、自動的に新しいパスを指す。
+    /// 移動先に登録が存在すれば、配下も含めて削除する。移動元が存在しなければ、なにもしない。
+    pub(super) fn rename(
+        &mut self,
+        old_parent: u64,
+        old_name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) {
+        let Some(inode) = self.get_inode(old_parent, old_name) else {
             return;
         };
-        list_guard.remove_left(&ino);
-        list_guard.insert(ino, new_path);
+        if self.get_inode(new_parent, new_name) == Some(inode) {
+            return;
+        }
+        self.remove(new_parent, new_name);
+        let alias_key = (old_parent, old_name.to_os_string());
+        if self.aliases.lock().unwrap().remove(&alias_key).is_some() {
+            self.aliases
+                .lock()
+                .unwrap()
+                .insert((new_parent, new_name.to_os_string()), inode);
+            return;
+        }
+        self.list
+            .lock()
+            .unwrap()
+            .insert(inode, (new_parent, new_name.to_os_string()));
+        self.detach_from_parent(old_parent, inode);
+        self.children
+            .lock()
+            .unwrap()
+            .entry(new_parent)
+            .or_default()
+            .insert(inode);
+    }
+
+    /// 親ディレクトリの子のリストから、inodeを外す。
+    fn detach_from_parent(&mut self, parent: u64, inode: u64) {
+        let mut children = self.children.lock().unwrap();
+        if let Some(c) = children.get_mut(&parent) {
+            c.remove(&inode);
+            if c.is_empty() {
+                children.remove(&parent);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod inode_test {
-    use super::Inodes;
+    use super::{Inodes, ROOT_INODE};
+    use std::ffi::OsStr;
     use std::path::Path;
 
+    fn os(s: &str) -> &OsStr {
+        OsStr::new(s)
+    }
+
     #[test]
     fn inode_add_test() {
-        let mut inodes = Inodes::new();
-        assert_eq!(inodes.add(""), 2);
-        assert_eq!(inodes.add(Path::new("test")), 3);
-        assert_eq!(inodes.add(Path::new("")), 2);
-        assert_eq!(inodes.add(Path::new("test")), 3);
-        assert_eq!(inodes.add(Path::new("test3")), 4);
-        assert_eq!(inodes.add(Path::new("/test")), 5);
-        assert_eq!(inodes.add(Path::new("test/")), 3);
+        let mut inodes = Inodes::new("/home/mito");
+        assert_eq!(inodes.add(ROOT_INODE, os("test")), 2);
+        assert_eq!(inodes.add(ROOT_INODE, os("test")), 2);
+        assert_eq!(inodes.add(ROOT_INODE, os("test3")), 3);
+        assert_eq!(inodes.add(2, os("test")), 4);
+        assert_eq!(inodes.add(2, os("test")), 4);
     }
 
+    /// /home/mito
+    ///  +- test(2)
+    ///  |   +- sub(5)
+    ///  |       +- file(6)
+    ///  +- test2(3)
+    ///  +- test3(4)
     fn make_inodes() -> Inodes {
-        let mut inodes = Inodes::new();
-        inodes.add(Path::new(""));
-        inodes.add(Path::new("test"));
-        inodes.add(Path::new("test2"));
-        inodes.add(Path::new("test3/"));
+        let mut inodes = Inodes::new("/home/mito");
+        inodes.add(ROOT_INODE, os("test"));
+        inodes.add(ROOT_INODE, os("test2"));
+        inodes.add(ROOT_INODE, os("test3"));
+        inodes.add(2, os("sub"));
+        inodes.add(5, os("file"));
         inodes
     }
 
     #[test]
     fn inodes_get_inode_test() {
         let inodes = make_inodes();
-        assert_eq!(inodes.get_inode(Path::new("")), Some(2));
-        assert_eq!(inodes.get_inode(Path::new("test4")), None);
-        assert_eq!(inodes.get_inode(Path::new("/test")), None);
-        assert_eq!(inodes.get_inode(Path::new("test3")), Some(5));
+        assert_eq!(inodes.get_inode(ROOT_INODE, os("test")), Some(2));
+        assert_eq!(inodes.get_inode(ROOT_INODE, os("test4")), None);
+        assert_eq!(inodes.get_inode(2, os("sub")), Some(5));
+        assert_eq!(inodes.get_inode(3, os("sub")), None);
     }
 
     #[test]
     fn inodes_get_path_test() {
         let inodes = make_inodes();
-        assert_eq!(inodes.get_path(2), Some(Path::new("").into()));
-        assert_eq!(inodes.get_path(4), Some(Path::new("test2").into()));
-        assert_eq!(inodes.get_path(6), None);
-        assert_eq!(inodes.get_path(4), Some(Path::new("test2/").into()));
+        assert_eq!(inodes.get_path(ROOT_INODE), Some("/home/mito".into()));
+        assert_eq!(inodes.get_path(3), Some("/home/mito/test2".into()));
+        assert_eq!(inodes.get_path(6), Some("/home/mito/test/sub/file".into()));
+        assert_eq!(inodes.get_path(7), None);
     }
 
     #[test]
     fn inodes_rename() {
         let mut inodes = make_inodes();
-        let old = Path::new("test2");
-        let new = Path::new("new_test");
-        let ino = inodes.get_inode(old).unwrap();
-        inodes.rename(old, new);
-        assert_eq!(inodes.get_path(ino), Some(new.into()));
+        inodes.rename(ROOT_INODE, os("test2"), ROOT_INODE, os("new_test"));
+        assert_eq!(inodes.get_path(3), Some("/home/mito/new_test".into()));
+        assert_eq!(inodes.get_inode(ROOT_INODE, os("test2")), None);
+
+        // ディレクトリの移動は、配下に反映される。
+        inodes.rename(ROOT_INODE, os("test"), 4, os("moved"));
+        assert_eq!(
+            inodes.get_path(5),
+            Some("/home/mito/test3/moved/sub".into())
+        );
+        assert_eq!(
+            inodes.get_path(6),
+            Some(Path::new("/home/mito/test3/moved/sub/file").into())
+        );
+
+        // 移動先の既存の登録は、配下ごと消える。
+        inodes.rename(ROOT_INODE, os("new_test"), 4, os("moved"));
+        assert_eq!(inodes.get_path(3), Some("/home/mito/test3/moved".into()));
+        assert_eq!(inodes.get_path(2), None);
+        assert_eq!(inodes.get_path(5), None);
+        assert_eq!(inodes.get_path(6), None);
 
         let mut inodes = make_inodes();
         let inodes2 = make_inodes();
-        inodes.rename(Path::new("nai"), Path::new("kawattenai"));
+        inodes.rename(ROOT_INODE, os("nai"), ROOT_INODE, os("kawattenai"));
         assert_eq!(*inodes.list.lock().unwrap(), *inodes2.list.lock().unwrap());
     }
+
+    #[test]
+    fn inodes_remove() {
+        let mut inodes = make_inodes();
+        assert_eq!(inodes.remove(ROOT_INODE, os("test")), Some(2));
+        assert_eq!(inodes.get_path(2), None);
+        assert_eq!(inodes.get_path(5), None);
+        assert_eq!(inodes.get_path(6), None);
+        assert_eq!(inodes.get_path(3), Some("/home/mito/test2".into()));
+        assert_eq!(inodes.remove(ROOT_INODE, os("test")), None);
+        // 同じ名前で作り直したものは、別のinodeになる。
+        assert_eq!(inodes.add(ROOT_INODE, os("test")), 7);
+        assert_eq!(inodes.get_inode(7, os("sub")), None);
+    }
+
+    #[test]
+    fn inodes_add_with_ino() {
+        let mut inodes = Inodes::with_first_inode("/", 1000);
+        assert_eq!(inodes.add_with_ino(ROOT_INODE, os("a"), 131), 131);
+        assert_eq!(inodes.add_with_ino(ROOT_INODE, os("a"), 131), 131);
+        // ハードリンク:同じinode番号が別の名前で使われていれば、その別名
+        assert_eq!(inodes.add_with_ino(ROOT_INODE, os("b"), 131), 131);
+        assert_eq!(inodes.get_inode(ROOT_INODE, os("b")), Some(131));
+        assert_eq!(inodes.get_path(131), Some("/a".into()));
+        inodes.remove(ROOT_INODE, os("b"));
+        // リモートで置き換えられた:古い登録は配下ごと消える
+        inodes.add_with_ino(131, os("child"), 200);
+        assert_eq!(inodes.add_with_ino(ROOT_INODE, os("a"), 140), 140);
+        assert_eq!(inodes.get_path(131), None);
+        assert_eq!(inodes.get_path(200), None);
+        assert_eq!(inodes.get_path(140), Some("/a".into()));
+    }
+
+    #[test]
+    fn inodes_alias() {
+        let mut inodes = make_inodes();
+        // test2(3)のハードリンクを、test/sub/link と test3/link に作る。
+        assert_eq!(inodes.lookup_alias(5, os("link"), 3), 3);
+        assert_eq!(inodes.lookup_alias(4, os("link"), 3), 3);
+        assert_eq!(inodes.get_inode(5, os("link")), Some(3));
+        assert_eq!(inodes.add(4, os("link")), 3);
+        assert_eq!(inodes.get_path(3), Some("/home/mito/test2".into()));
+
+        // 別名の名前変更・削除は、inodeに影響しない。
+        inodes.rename(4, os("link"), 4, os("link2"));
+        assert_eq!(inodes.get_inode(4, os("link")), None);
+        assert_eq!(inodes.get_inode(4, os("link2")), Some(3));
+        assert_eq!(inodes.remove(4, os("link2")), Some(3));
+        assert_eq!(inodes.get_path(3), Some("/home/mito/test2".into()));
+
+        // 主の名前を削除すると、別名が主の名前になる。
+        assert_eq!(inodes.remove(ROOT_INODE, os("test2")), Some(3));
+        assert_eq!(inodes.get_path(3), Some("/home/mito/test/sub/link".into()));
+        assert_eq!(inodes.get_inode(ROOT_INODE, os("test2")), None);
+        assert!(inodes.lookup_inode(3));
+
+        // ディレクトリごと削除すると、配下の別名も消える。
+        inodes.lookup_alias(ROOT_INODE, os("top"), 3);
+        inodes.lookup_alias(5, os("link3"), 6);
+        inodes.remove(ROOT_INODE, os("test"));
+        assert_eq!(inodes.get_path(3), Some("/home/mito/top".into()));
+        assert_eq!(inodes.get_path(6), None);
+        assert_eq!(inodes.get_inode(5, os("link3")), None);
+
+        // 未登録のinodeは、連番で登録する。
+        assert_eq!(inodes.lookup_alias(ROOT_INODE, os("x"), 100), 7);
+    }
+
+    #[test]
+    fn inodes_lookup_forget() {
+        let mut inodes = Inodes::new("/");
+        let dir = inodes.lookup(ROOT_INODE, os("dir"));
+        let file = inodes.lookup(dir, os("file"));
+        assert_eq!(inodes.lookup(dir, os("file")), file);
+        inodes.forget(file, 1);
+        assert_eq!(inodes.get_path(file), Some("/dir/file".into()));
+
+        // 配下が残っている間は、参照がなくなっても削除されない。
+        inodes.forget(dir, 1);
+        assert_eq!(inodes.get_path(dir), Some("/dir".into()));
+        inodes.forget(file, 1);
+        assert_eq!(inodes.get_path(file), None);
+        assert_eq!(inodes.get_path(dir), None);
+        assert_eq!(inodes.get_inode(ROOT_INODE, os("dir")), None);
+
+        // 削除されたinodeへのforgetは無視される。
+        let f = inodes.lookup(ROOT_INODE, os("f"));
+        inodes.remove(ROOT_INODE, os("f"));
+        inodes.forget(f, 1);
+        assert_eq!(inodes.get_path(f), None);
+        assert!(inodes.children.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn inodes_keep_forgotten() {
+        let mut inodes = Inodes::new("/");
+        inodes.set_keep_forgotten(true);
+        let dir = inodes.lookup(ROOT_INODE, os("dir"));
+        let file = inodes.lookup(dir, os("file"));
+        inodes.forget(file, 1);
+        inodes.forget(dir, 1);
+        assert_eq!(inodes.get_path(file), Some("/dir/file".into()));
+        assert_eq!(inodes.get_parent(file), Some(dir));
+        assert_eq!(inodes.get_parent(ROOT_INODE), Some(ROOT_INODE));
+        assert!(inodes.lookup_inode(file));
+        assert!(inodes.lookup_inode(ROOT_INODE));
+        assert!(!inodes.lookup_inode(100));
+    }
+
+    #[test]
+    fn inodes_keep_forgotten_max() {
+        let mut inodes = Inodes::new("/");
+        inodes.set_keep_forgotten(true);
+        inodes.forgotten_max = 2;
+        let a = inodes.lookup(ROOT_INODE, os("a"));
+        let b = inodes.lookup(ROOT_INODE, os("b"));
+        let c = inodes.lookup(ROOT_INODE, os("c"));
+        inodes.forget(a, 1);
+        inodes.forget(b, 1);
+        // 再度参照されたものは、保持の対象から外れる。
+        assert!(inodes.lookup_inode(a));
+        inodes.forget(c, 1);
+        assert_eq!(inodes.forgotten.len(), 2);
+        inodes.forget(a, 1);
+        // 上限を超えたので、一番古いbが削除される。
+        assert_eq!(inodes.get_path(b), None);
+        assert_eq!(inodes.get_path(c), Some("/c".into()));
+        assert_eq!(inodes.get_path(a), Some("/a".into()));
+    }
 }
@@ -0,0 +1,686 @@
+//! ファイルロック管理モジュール
+//! POSIXロック(fcntl)を、ローカルで管理し、リモートとはfcntl(2)のバイト範囲ロックで調整する。
+//! リモート側のロックは、inode毎に常駐させたperlのヘルパーが、このホストの全ownerの分をまとめて保持する。
+//! flockは、fuserがFUSE_LK_FLOCKを渡さずPOSIXロックと区別できないので、ここでは扱わない。
+//! 待ち合わせ(F_SETLKW)の要求は、ロックと衝突すれば、解除されるまで応答を保留する。
+//! リモートのロックの解除は通知されないので、保留中の要求は、別スレッドから間をおいて再試行する。
+
+use super::{remote_cmd, Error};
+use fuser::ReplyEmpty;
+use log::warn;
+use ssh2::{Channel, Session};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// ロックの範囲の終端として、カーネルが使う値(OFFSET_MAX)
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+/// 保留中の要求を、再試行する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// リモートでfcntlのロックを保持し続けるヘルパー
+/// 1行目に"ready"を出力した後、標準入力から"操作 種類 開始 長さ"の行を受け取る。
+/// 操作は"s"(設定)か"g"(確認)、種類は"r"(共有)、"w"(排他)、"u"(解除)、長さ0はファイル末尾まで。
+/// 結果は"ok"(確認では"ok 種類 開始 長さ")、またはエラーメッセージで返す。
+/// 標準入力が閉じられると終了し、ロックも解除される。struct flockは、64ビットLinuxの配置。
+const HELPER_SCRIPT: &str = r#"use Fcntl; $| = 1;
+open(my $f, "+<", $ARGV[0]) or open($f, "<", $ARGV[0]) or die "$!\n";
+my %t = (r => F_RDLCK, w => F_WRLCK, u => F_UNLCK);
+print "ready\n";
+while (my $l = <STDIN>) {
+    my ($op, $typ, $start, $len) = split " ", $l;
+    my $fl = pack("s s x4 q q i x4", $t{$typ}, 0, $start, $len, 0);
+    if (!fcntl($f, $op eq "g" ? F_GETLK : F_SETLK, $fl)) {
+        print "$!\n";
+    } elsif ($op eq "g") {
+        my ($t, $s, $n) = (unpack("s s x4 q q i x4", $fl))[0, 2, 3];
+        print "ok ", ($t == F_WRLCK ? "w" : $t == F_RDLCK ? "r" : "u"), " $s $n\n";
+    } else {
+        print "ok\n";
+    }
+}"#;
+
+/// ローカルで保持しているロック
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lock {
+    owner: u64,
+    start: u64,
+    end: u64,
+    typ: i32,
+    pid: u32,
+}
+
+/// リモートのロックを保持するヘルパーのチャネル
+struct Helper {
+    channel: Channel,
+}
+
+impl Helper {
+    /// ヘルパーを起動し、ファイルを開くまで待つ。
+    fn start(session: &Session, path: &Path) -> Result<Self, Error> {
+        let command = format!(
+            "perl -e {} -- {}",
+            remote_cmd::quote(HELPER_SCRIPT)?,
+            remote_cmd::quote(path)?
+        );
+        let mut helper = Self {
+            channel: remote_cmd::spawn(session, &command)?,
+        };
+        match helper.read_line()?.as_str() {
+            "ready" => Ok(helper),
+            _ => Err(Error(libc::EIO)),
+        }
+    }
+
+    /// ヘルパーを起動せずに、リモートのロックを一度だけ確認する。
+    fn probe(
+        session: &Session,
+        path: &Path,
+        start: u64,
+        end: u64,
+        typ: i32,
+    ) -> Result<(u64, u64, i32, u32), Error> {
+        let command = format!(
+            "printf '%s\\n' {} | perl -e {} -- {}",
+            remote_cmd::quote(Self::op("g", typ, start, end))?,
+            remote_cmd::quote(HELPER_SCRIPT)?,
+            remote_cmd::quote(path)?
+        );
+        let out = remote_cmd::exec(session, &command)?.into_result()?;
+        // 1行目は"ready"
+        let out = String::from_utf8_lossy(&out);
+        let line = out.lines().nth(1).ok_or(Error(libc::EIO))?;
+        Self::parse_getlk(Self::result(line)?, start, end)
+    }
+
+    /// リモートのロックを設定、または解除(typがF_UNLCK)する。
+    fn setlk(&mut self, start: u64, end: u64, typ: i32) -> Result<(), Error> {
+        self.request(&Self::op("s", typ, start, end))?;
+        Ok(())
+    }
+
+    /// 他のプロセス(他のホストを含む)のリモートのロックで、衝突するものを返す。
+    /// ヘルパー自身が保持するロックは、対象にならない。
+    fn getlk(&mut self, start: u64, end: u64, typ: i32) -> Result<(u64, u64, i32, u32), Error> {
+        let result = self.request(&Self::op("g", typ, start, end))?;
+        Self::parse_getlk(&result, start, end)
+    }
+
+    /// ヘルパーへの要求の行を作る。
+    fn op(op: &str, typ: i32, start: u64, end: u64) -> String {
+        let typ = match typ {
+            libc::F_WRLCK => 'w',
+            libc::F_RDLCK => 'r',
+            _ => 'u',
+        };
+        let len = if end >= OFFSET_MAX {
+            0
+        } else {
+            end - start + 1
+        };
+        format!("{op} {typ} {start} {len}")
+    }
+
+    /// 確認の結果("種類 開始 長さ")を、(start, end, typ, pid)にする。
+    /// 衝突がなければ、要求の範囲でtypをF_UNLCKとする。他のホストのpidは意味がないので0。
+    fn parse_getlk(result: &str, start: u64, end: u64) -> Result<(u64, u64, i32, u32), Error> {
+        let f: Vec<&str> = result.split_whitespace().collect();
+        let [typ, s, n] = f[..] else {
+            return Err(Error(libc::EIO));
+        };
+        let typ = match typ {
+            "w" => libc::F_WRLCK,
+            "r" => libc::F_RDLCK,
+            _ => return Ok((start, end, libc::F_UNLCK, 0)),
+        };
+        let s = s.parse::<u64>().map_err(|_| Error(libc::EIO))?;
+        let n = n.parse::<u64>().map_err(|_| Error(libc::EIO))?;
+        let e = if n == 0 { OFFSET_MAX } else { s + n - 1 };
+        Ok((s, e, typ, 0))
+    }
+
+    /// ヘルパーの応答が"ok"で始まれば残りを、それ以外はエラーメッセージからエラーを返す。
+    fn result(line: &str) -> Result<&str, Error> {
+        match line.strip_prefix("ok") {
+            Some(rest) => Ok(rest.trim()),
+            None => Err(Error(remote_cmd::message_errno(line))),
+        }
+    }
+
+    /// ヘルパーに操作を要求し、結果を返す。
+    fn request(&mut self, op: &str) -> Result<String, Error> {
+        self.channel.write_all(format!("{op}\n").as_bytes())?;
+        self.channel.flush()?;
+        let line = self.read_line()?;
+        Ok(Self::result(&line)?.to_string())
+    }
+
+    /// ヘルパーの出力を1行読む。ヘルパーが終了していれば、そのエラーを返す。
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            if self.channel.read(&mut byte)? == 0 {
+                return Err(self.exit_error());
+            }
+            if byte[0] == b'\n' {
+                return Ok(String::from_utf8_lossy(&line).into_owned());
+            }
+            line.push(byte[0]);
+        }
+    }
+
+    /// 終了したヘルパーのエラーを、標準エラー出力と終了ステータスから求める。
+    fn exit_error(&mut self) -> Error {
+        let mut stderr = String::new();
+        let _ = self.channel.stderr().read_to_string(&mut stderr);
+        let _ = self.channel.wait_close();
+        match self.channel.exit_status() {
+            Ok(126 | 127) => Error(libc::ENOSYS),
+            _ => Error(remote_cmd::message_errno(&stderr)),
+        }
+    }
+
+    /// 標準入力を閉じて、ヘルパーを終了させる。(リモートのロックは解除される)
+    fn close(mut self) {
+        let _ = self.channel.send_eof();
+        let _ = self.channel.wait_close();
+    }
+}
+
+/// inode毎のロックの状態
+#[derive(Default)]
+struct InodeLocks {
+    locks: Vec<Lock>,
+    helper: Option<Helper>,
+}
+
+/// 保留中のロック要求を、間をおいて再試行するスレッドを起動する。
+/// リモートのロックの解除は通知されないので、FUSEの要求を処理するスレッドを止めないよう、別スレッドで確認する。
+/// Locksが破棄されると、終了する。
+pub(super) fn spawn_poller(locks: &Arc<Mutex<Locks>>, session: Session) {
+    let locks = Arc::downgrade(locks);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let Some(locks) = locks.upgrade() else {
+            break;
+        };
+        locks.lock().unwrap().poll(&session);
+    });
+}
+
+/// ロック要求への応答
+pub(super) trait LockReply {
+    fn ok(self);
+    fn error(self, err: i32);
+}
+
+impl LockReply for ReplyEmpty {
+    fn ok(self) {
+        ReplyEmpty::ok(self)
+    }
+
+    fn error(self, err: i32) {
+        ReplyEmpty::error(self, err)
+    }
+}
+
+/// ロックとの衝突で、応答を保留しているロック要求
+struct Waiter<R> {
+    path: PathBuf,
+    lock: Lock,
+    reply: R,
+}
+
+/// ファイルロック管理構造体
+pub(super) struct Locks<R: LockReply = ReplyEmpty> {
+    list: HashMap<u64, InodeLocks>,
+    /// inode -> 応答を保留している要求(要求順)
+    waiting: HashMap<u64, Vec<Waiter<R>>>,
+}
+
+impl<R: LockReply> Locks<R> {
+    pub(super) fn new() -> Self {
+        Self {
+            list: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// 指定範囲のロックと衝突するロックを返す。(start, end, typ, pid)
+    /// 衝突するものがなければ、typをF_UNLCKとして返す。
+    /// このマウントのロックに衝突がなければ、リモートの他のロックを確認する。
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn getlk(
+        &mut self,
+        session: &Session,
+        path: &Path,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+    ) -> Result<(u64, u64, i32, u32), Error> {
+        if let Some(entry) = self.list.get_mut(&ino) {
+            if let Some(l) = Self::conflict(&entry.locks, owner, start, end, typ) {
+                return Ok((l.start, l.end, l.typ, l.pid));
+            }
+            if let Some(helper) = &mut entry.helper {
+                return helper.getlk(start, end, typ);
+            }
+        }
+        Helper::probe(session, path, start, end, typ)
+    }
+
+    /// ロックを設定、または解除(typがF_UNLCK)し、応答する。
+    /// 衝突するロックがあればEAGAIN。ただし、sleepなら、応答を保留して解除を待つ。
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn setlk(
+        &mut self,
+        session: &Session,
+        path: &Path,
+        ino: u64,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: R,
+    ) {
+        let lock = Lock {
+            owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        let result = self.set(session, path, ino, lock);
+        if sleep && matches!(result, Err(Error(libc::EAGAIN))) {
+            let path = path.to_path_buf();
+            let waiter = Waiter { path, lock, reply };
+            self.waiting.entry(ino).or_default().push(waiter);
+            return;
+        }
+        Self::reply(reply, result);
+        // 解除や共有ロックへの変更で、待っている要求が通せるようになることがある。
+        self.wake(session, ino);
+    }
+
+    /// 保留している要求を、要求順に再試行する。まだロックと衝突するものは、保留したままとする。
+    /// ローカルのロックと衝突するものは、リモートに問い合わせずに保留する。
+    fn wake(&mut self, session: &Session, ino: u64) {
+        let Some(waiters) = self.waiting.remove(&ino) else {
+            return;
+        };
+        for w in waiters {
+            if self.local_conflict(ino, &w.lock) {
+                self.waiting.entry(ino).or_default().push(w);
+                continue;
+            }
+            match self.set(session, &w.path, ino, w.lock) {
+                Err(Error(libc::EAGAIN)) => self.waiting.entry(ino).or_default().push(w),
+                result => Self::reply(w.reply, result),
+            }
+        }
+    }
+
+    /// 保留している全ての要求を再試行する。(リモートのロックの解除を確認するため)
+    fn poll(&mut self, session: &Session) {
+        let inos: Vec<u64> = self.waiting.keys().copied().collect();
+        for ino in inos {
+            self.wake(session, ino);
+        }
+    }
+
+    /// ロック要求の結果を応答する。リモートでperlが使えなければ、ENOLCKとする。
+    fn reply(reply: R, result: Result<(), Error>) {
+        match result {
+            Ok(_) => reply.ok(),
+            Err(Error(libc::ENOSYS)) => {
+                warn!("[setlk] リモートでperlが使えないので、ロックできない。");
+                reply.error(libc::ENOLCK);
+            }
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    /// このマウントの他のownerのロックと衝突するかどうか。
+    fn local_conflict(&self, ino: u64, lock: &Lock) -> bool {
+        self.list.get(&ino).is_some_and(|e| {
+            Self::conflict(&e.locks, lock.owner, lock.start, lock.end, lock.typ).is_some()
+        })
+    }
+
+    /// ロックを設定、または解除(typがF_UNLCK)する。衝突するロックがあればEAGAIN。
+    fn set(&mut self, session: &Session, path: &Path, ino: u64, lock: Lock) -> Result<(), Error> {
+        let Lock {
+            owner,
+            start,
+            end,
+            typ,
+            ..
+        } = lock;
+        let entry = self.list.entry(ino).or_default();
+        if typ != libc::F_UNLCK && Self::conflict(&entry.locks, owner, start, end, typ).is_some() {
+            return Err(Error(libc::EAGAIN));
+        }
+        let mut locks = entry.locks.clone();
+        Self::apply(&mut locks, lock);
+        let result = if typ == libc::F_UNLCK {
+            Self::unlock_remote(entry, &[(start, end)], &locks)
+        } else {
+            Self::lock_remote(entry, session, path, start, end, typ)
+        };
+        if result.is_ok() {
+            entry.locks = locks;
+        }
+        self.cleanup(ino);
+        result
+    }
+
+    /// ownerの持つロックを全て解除する。(ファイルのクローズ時)
+    /// ownerの保留中の要求は、要求したプロセスがもういない(シグナルで中断された)ので、EINTRで応答して破棄する。
+    /// 解除を待っている他の要求があれば、再試行する。
+    pub(super) fn release_owner(&mut self, session: &Session, ino: u64, owner: u64) {
+        if let Some(waiters) = self.waiting.remove(&ino) {
+            let (dropped, waiters): (Vec<_>, Vec<_>) =
+                waiters.into_iter().partition(|w| w.lock.owner == owner);
+            for w in dropped {
+                w.reply.error(libc::EINTR);
+            }
+            if !waiters.is_empty() {
+                self.waiting.insert(ino, waiters);
+            }
+        }
+        let Some(entry) = self.list.get_mut(&ino) else {
+            return;
+        };
+        let (released, locks): (Vec<Lock>, Vec<Lock>) =
+            entry.locks.iter().partition(|l| l.owner == owner);
+        let ranges: Vec<(u64, u64)> = released.iter().map(|l| (l.start, l.end)).collect();
+        let _ = Self::unlock_remote(entry, &ranges, &locks);
+        entry.locks = locks;
+        self.cleanup(ino);
+        self.wake(session, ino);
+    }
+
+    /// ロックがなくなったinodeの登録を削除し、ヘルパーを終了させる。
+    fn cleanup(&mut self, ino: u64) {
+        if self.list.get(&ino).is_some_and(|e| e.locks.is_empty()) {
+            if let Some(helper) = self.list.remove(&ino).and_then(|e| e.helper) {
+                helper.close();
+            }
+        }
+    }
+
+    /// リモートの範囲を、新しい種類でロックする。必要なら、ヘルパーを起動する。
+    /// 他のownerのロックとは衝突しないので、範囲をそのまま新しい種類にすればよい。
+    fn lock_remote(
+        entry: &mut InodeLocks,
+        session: &Session,
+        path: &Path,
+        start: u64,
+        end: u64,
+        typ: i32,
+    ) -> Result<(), Error> {
+        let helper = match &mut entry.helper {
+            Some(h) => h,
+            None => entry.helper.insert(Helper::start(session, path)?),
+        };
+        helper.setlk(start, end, typ)
+    }
+
+    /// 解除した範囲のうち、残るロック(他のowner)のない部分を、リモートでも解除する。
+    /// ロックが残らない場合は、ヘルパーの終了で解除されるので、なにもしない。
+    fn unlock_remote(
+        entry: &mut InodeLocks,
+        ranges: &[(u64, u64)],
+        remaining: &[Lock],
+    ) -> Result<(), Error> {
+        let Some(helper) = &mut entry.helper else {
+            return Ok(());
+        };
+        if remaining.is_empty() {
+            return Ok(());
+        }
+        for &(start, end) in ranges {
+            for (s, e) in Self::gaps(start, end, remaining) {
+                helper.setlk(s, e, libc::F_UNLCK)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 範囲のうち、どのロックにも含まれない部分を返す。
+    fn gaps(start: u64, end: u64, locks: &[Lock]) -> Vec<(u64, u64)> {
+        let mut covered: Vec<(u64, u64)> = locks
+            .iter()
+            .filter(|l| l.start <= end && start <= l.end)
+            .map(|l| (l.start, l.end))
+            .collect();
+        covered.sort_unstable();
+        let mut gaps = Vec::new();
+        let mut next = start;
+        for (s, e) in covered {
+            if s > next {
+                gaps.push((next, s - 1));
+            }
+            if e >= end {
+                return gaps;
+            }
+            next = next.max(e + 1);
+        }
+        gaps.push((next, end));
+        gaps
+    }
+
+    /// 他のownerのロックで、指定範囲のロックと衝突するものを探す。
+    fn conflict(locks: &[Lock], owner: u64, start: u64, end: u64, typ: i32) -> Option<&Lock> {
+        locks.iter().find(|l| {
+            l.owner != owner
+                && l.start <= end
+                && start <= l.end
+                && (typ == libc::F_WRLCK || l.typ == libc::F_WRLCK)
+        })
+    }
+
+    /// ownerのロックに、新しいロック(または解除)を適用する。
+    /// 範囲が重なる部分は、新しいものに置き換える。
+    fn apply(locks: &mut Vec<Lock>, new: Lock) {
+        let mut result = Vec::with_capacity(locks.len() + 2);
+        for l in locks.drain(..) {
+            if l.owner != new.owner || l.end < new.start || new.end < l.start {
+                result.push(l);
+                continue;
+            }
+            if l.start < new.start {
+                result.push(Lock {
+                    end: new.start - 1,
+                    ..l
+                });
+            }
+            if l.end > new.end {
+                result.push(Lock {
+                    start: new.end + 1,
+                    ..l
+                });
+            }
+        }
+        if new.typ != libc::F_UNLCK {
+            result.push(new);
+        }
+        *locks = result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    type TestLocks = Locks<TestReply>;
+
+    /// 応答を記録する、テスト用の応答(0はok)
+    #[derive(Clone, Default)]
+    struct TestReply(Rc<RefCell<Option<i32>>>);
+
+    impl TestReply {
+        fn get(&self) -> Option<i32> {
+            *self.0.borrow()
+        }
+    }
+
+    impl LockReply for TestReply {
+        fn ok(self) {
+            *self.0.borrow_mut() = Some(0);
+        }
+
+        fn error(self, err: i32) {
+            *self.0.borrow_mut() = Some(err);
+        }
+    }
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+        Lock {
+            owner,
+            start,
+            end,
+            typ,
+            pid: 0,
+        }
+    }
+
+    #[test]
+    fn conflict_test() {
+        let locks = vec![
+            lock(1, 0, 99, libc::F_RDLCK),
+            lock(2, 200, OFFSET_MAX, libc::F_WRLCK),
+        ];
+        // 共有ロック同士は衝突しない
+        assert_eq!(TestLocks::conflict(&locks, 3, 50, 60, libc::F_RDLCK), None);
+        assert_eq!(
+            TestLocks::conflict(&locks, 3, 50, 60, libc::F_WRLCK),
+            Some(&locks[0])
+        );
+        assert_eq!(
+            TestLocks::conflict(&locks, 3, 150, 250, libc::F_RDLCK),
+            Some(&locks[1])
+        );
+        assert_eq!(
+            TestLocks::conflict(&locks, 3, 100, 199, libc::F_WRLCK),
+            None
+        );
+        // 自分のロックとは衝突しない
+        assert_eq!(
+            TestLocks::conflict(&locks, 2, 300, 400, libc::F_WRLCK),
+            None
+        );
+    }
+
+    #[test]
+    fn release_owner_waiting_test() {
+        let session = Session::new().unwrap();
+        let path = Path::new("/a");
+        let mut locks = TestLocks::new();
+        // owner 1が保持しているロック(リモートのヘルパーなし)
+        locks.list.entry(2).or_default().locks = vec![lock(1, 0, 99, libc::F_WRLCK)];
+        let setlk = |locks: &mut TestLocks, owner, sleep| {
+            let reply = TestReply::default();
+            let (start, end, typ) = (0, 9, libc::F_WRLCK);
+            locks.setlk(
+                &session,
+                path,
+                2,
+                owner,
+                start,
+                end,
+                typ,
+                0,
+                sleep,
+                reply.clone(),
+            );
+            reply
+        };
+        assert_eq!(setlk(&mut locks, 2, false).get(), Some(libc::EAGAIN));
+        // 待ち合わせは、応答を保留する
+        let waiting2 = setlk(&mut locks, 2, true);
+        let waiting3 = setlk(&mut locks, 3, true);
+        assert_eq!((waiting2.get(), waiting3.get()), (None, None));
+        assert_eq!(locks.waiting[&2].len(), 2);
+        // ローカルのロックと衝突している間は、再試行しても保留したまま
+        locks.poll(&session);
+        assert_eq!((waiting2.get(), waiting3.get()), (None, None));
+        assert_eq!(locks.waiting[&2].len(), 2);
+        // 待っていたプロセスが中断されてクローズすると、その要求はEINTRで破棄する
+        locks.release_owner(&session, 2, 2);
+        assert_eq!(waiting2.get(), Some(libc::EINTR));
+        assert_eq!(waiting3.get(), None);
+        assert_eq!(locks.waiting[&2].len(), 1);
+        assert_eq!(locks.list[&2].locks, vec![lock(1, 0, 99, libc::F_WRLCK)]);
+        locks.release_owner(&session, 2, 3);
+        assert_eq!(waiting3.get(), Some(libc::EINTR));
+        assert!(locks.waiting.is_empty());
+    }
+
+    #[test]
+    fn gaps_test() {
+        let locks = vec![
+            lock(1, 10, 19, libc::F_RDLCK),
+            lock(2, 15, 29, libc::F_RDLCK),
+            lock(2, 50, OFFSET_MAX, libc::F_WRLCK),
+        ];
+        assert_eq!(
+            TestLocks::gaps(0, OFFSET_MAX, &locks),
+            vec![(0, 9), (30, 49)]
+        );
+        assert_eq!(TestLocks::gaps(12, 28, &locks), vec![]);
+        assert_eq!(TestLocks::gaps(25, 40, &locks), vec![(30, 40)]);
+        assert_eq!(TestLocks::gaps(0, 5, &[]), vec![(0, 5)]);
+    }
+
+    #[test]
+    fn helper_op_test() {
+        assert_eq!(Helper::op("s", libc::F_WRLCK, 10, 19), "s w 10 10");
+        assert_eq!(Helper::op("g", libc::F_RDLCK, 5, OFFSET_MAX), "g r 5 0");
+        assert_eq!(Helper::op("s", libc::F_UNLCK, 0, 0), "s u 0 1");
+        assert_eq!(
+            Helper::parse_getlk("w 10 10", 0, OFFSET_MAX).unwrap(),
+            (10, 19, libc::F_WRLCK, 0)
+        );
+        assert_eq!(
+            Helper::parse_getlk("r 20 0", 0, 99).unwrap(),
+            (20, OFFSET_MAX, libc::F_RDLCK, 0)
+        );
+        assert_eq!(
+            Helper::parse_getlk("u 0 0", 5, 9).unwrap(),
+            (5, 9, libc::F_UNLCK, 0)
+        );
+        assert_eq!(Helper::result("ok w 1 2").unwrap(), "w 1 2");
+        assert_eq!(
+            Helper::result("Resource temporarily unavailable").map_err(|e| e.0),
+            Err(libc::EAGAIN)
+        );
+    }
+
+    #[test]
+    fn apply_test() {
+        let mut locks = vec![lock(1, 0, 99, libc::F_WRLCK), lock(2, 0, 99, libc::F_RDLCK)];
+        // 中央部分を共有ロックに変更すると、3つに分かれる
+        TestLocks::apply(&mut locks, lock(1, 10, 19, libc::F_RDLCK));
+        assert_eq!(
+            locks,
+            vec![
+                lock(1, 0, 9, libc::F_WRLCK),
+                lock(1, 20, 99, libc::F_WRLCK),
+                lock(2, 0, 99, libc::F_RDLCK),
+                lock(1, 10, 19, libc::F_RDLCK),
+            ]
+        );
+        TestLocks::apply(&mut locks, lock(1, 0, OFFSET_MAX, libc::F_UNLCK));
+        assert_eq!(locks, vec![lock(2, 0, 99, libc::F_RDLCK)]);
+    }
+}
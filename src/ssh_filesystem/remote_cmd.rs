@@ -0,0 +1,230 @@
+//! リモートコマンド実行モジュール
+//! SFTPでは扱えない操作を、sshのexecチャネル経由でリモート側のコマンドとして実行する。
+
+use super::Error;
+use log::debug;
+use ssh2::{Channel, Session};
+use std::{
+    ffi::OsStr,
+    io::{Read, Write},
+    os::unix::ffi::OsStrExt,
+};
+
+/// リモートコマンドの実行結果
+#[derive(Debug)]
+pub(super) struct CmdOutput {
+    pub(super) status: i32,
+    pub(super) stdout: Vec<u8>,
+    pub(super) stderr: Vec<u8>,
+}
+
+impl CmdOutput {
+    /// 終了ステータスが0であれば標準出力を返し、
+    /// それ以外は、標準エラー出力から推定したエラー番号を返す。
+    pub(super) fn into_result(self) -> Result<Vec<u8>, Error> {
+        if self.status == 0 {
+            Ok(self.stdout)
+        } else {
+            Err(Error(self.errno()))
+        }
+    }
+
+    /// 標準エラー出力のメッセージから、エラー番号を推定する。
+    /// コマンド自体が存在しない、あるいはオプションが解釈できない場合はENOSYSを返す。
+    /// (呼び出し側はENOSYSを見て、代替手段に切り替えることができる。)
+    pub(super) fn errno(&self) -> i32 {
+        if self.status == 126 || self.status == 127 {
+            return libc::ENOSYS;
+        }
+        message_errno(&String::from_utf8_lossy(&self.stderr))
+    }
+}
+
+/// エラーメッセージ(strerrorの文字列を含むもの)から、エラー番号を推定する。
+/// 推定できない場合はEIO。
+pub(super) fn message_errno(msg: &str) -> i32 {
+    const MESSAGES: &[(&str, i32)] = &[
+        ("No such file or directory", libc::ENOENT),
+        ("Permission denied", libc::EACCES),
+        ("Operation not permitted", libc::EPERM),
+        ("File exists", libc::EEXIST),
+        ("Not a directory", libc::ENOTDIR),
+        ("Is a directory", libc::EISDIR),
+        ("Directory not empty", libc::ENOTEMPTY),
+        ("No space left on device", libc::ENOSPC),
+        ("Disk quota exceeded", libc::EDQUOT),
+        ("Read-only file system", libc::EROFS),
+        ("Operation not supported", libc::ENOTSUP),
+        ("Invalid cross-device link", libc::EXDEV),
+        ("Too many links", libc::EMLINK),
+        ("File name too long", libc::ENAMETOOLONG),
+        ("Too many levels of symbolic links", libc::ELOOP),
+        ("No data available", libc::ENODATA),
+        ("No such attribute", libc::ENODATA),
+        ("Resource temporarily unavailable", libc::EAGAIN),
+        ("No such device or address", libc::ENXIO),
+        ("Numerical result out of range", libc::ERANGE),
+        ("Argument list too long", libc::E2BIG),
+        ("File too large", libc::EFBIG),
+        ("Invalid argument", libc::EINVAL),
+        ("Function not implemented", libc::ENOSYS),
+        ("invalid option", libc::ENOSYS),
+        ("unrecognized option", libc::ENOSYS),
+        ("illegal option", libc::ENOSYS),
+        ("invalid input flag", libc::ENOSYS),
+        ("invalid output flag", libc::ENOSYS),
+        ("unknown operand", libc::ENOSYS),
+        ("invalid argument '", libc::ENOSYS),
+    ];
+    MESSAGES
+        .iter()
+        .find(|(m, _)| msg.contains(m))
+        .map_or(libc::EIO, |(_, e)| *e)
+}
+
+/// リモートでコマンドを起動し、終了を待たずにチャネルを返す。
+/// 常駐させて標準入出力でやりとりするヘルパーに使う。ロケールはexecと同じくCに固定する。
+pub(super) fn spawn(session: &Session, command: &str) -> Result<Channel, Error> {
+    let command = format!("LC_ALL=C {command}");
+    debug!("[remote_cmd::spawn] command: {}", &command);
+    let mut channel = session.channel_session()?;
+    channel.exec(&command)?;
+    Ok(channel)
+}
+
+/// リモートでコマンドを実行し、終了を待って結果を返す。
+/// メッセージからエラー番号を推定できるよう、ロケールはCに固定して実行する。
+pub(super) fn exec(session: &Session, command: &str) -> Result<CmdOutput, Error> {
+    let command = format!("LC_ALL=C {command}");
+    debug!("[remote_cmd::exec] command: {}", &command);
+    let mut channel = session.channel_session()?;
+    channel.exec(&command)?;
+    wait(channel)
+}
+
+/// execと同じ。ただし、inputをコマンドの標準入力に渡す。
+/// コマンド行では長さの上限(E2BIG)にかかる、大きなデータを渡すのに使う。
+pub(super) fn exec_with_input(
+    session: &Session,
+    command: &str,
+    input: &[u8],
+) -> Result<CmdOutput, Error> {
+    let mut channel = spawn(session, command)?;
+    channel.write_all(input)?;
+    channel.send_eof()?;
+    wait(channel)
+}
+
+/// コマンドの終了を待ち、結果を返す。
+fn wait(mut channel: Channel) -> Result<CmdOutput, Error> {
+    let mut stdout = Vec::<u8>::new();
+    channel.read_to_end(&mut stdout)?;
+    let mut stderr = Vec::<u8>::new();
+    channel.stderr().read_to_end(&mut stderr)?;
+    channel.wait_close()?;
+    let status = channel.exit_status()?;
+    Ok(CmdOutput {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// シェルに渡す引数を、シングルクォートで囲んで返す。
+/// コマンド行は文字列でしか渡せないので、UTF-8として解釈できない引数は、
+/// printfで元のバイト列に戻す形("$(printf '\ooo')")にする。
+/// コマンド置換は末尾の改行を削るので、末尾の改行は、続けてシングルクォートで囲んで付け加える。
+pub(super) fn quote<S: AsRef<OsStr>>(arg: S) -> Result<String, Error> {
+    let arg = arg.as_ref();
+    if let Some(arg) = arg.to_str() {
+        return Ok(format!("'{}'", arg.replace('\'', r"'\''")));
+    }
+    let bytes = arg.as_bytes();
+    let body_len = bytes.iter().rposition(|b| *b != b'\n').map_or(0, |i| i + 1);
+    let (body, newlines) = bytes.split_at(body_len);
+    let mut format = String::with_capacity(body.len() * 4);
+    for b in body {
+        match b {
+            b' '..=b'~' if !matches!(b, b'\'' | b'\\' | b'%') => format.push(*b as char),
+            _ => format.push_str(&format!("\\{b:03o}")),
+        }
+    }
+    let mut word = format!("\"$(printf '{format}')\"");
+    if !newlines.is_empty() {
+        word.push('\'');
+        word.push_str(&"\n".repeat(newlines.len()));
+        word.push('\'');
+    }
+    Ok(word)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quote_test() {
+        assert_eq!(quote("abc").unwrap(), "'abc'");
+        assert_eq!(quote("a b").unwrap(), "'a b'");
+        assert_eq!(quote("it's").unwrap(), r"'it'\''s'");
+        assert_eq!(quote("").unwrap(), "''");
+        // UTF-8でないもの
+        let latin1 = OsStr::from_bytes(b"caf\xe9 'x'%");
+        assert_eq!(
+            quote(latin1).unwrap(),
+            r#""$(printf 'caf\351 \047x\047\045')""#
+        );
+        // 末尾の改行は、コマンド置換の外に置く
+        assert_eq!(
+            quote(OsStr::from_bytes(b"\xff\n")).unwrap(),
+            "\"$(printf '\\377')\"'\n'"
+        );
+        assert_eq!(
+            quote(OsStr::from_bytes(b"\n\xff\n\n")).unwrap(),
+            "\"$(printf '\\012\\377')\"'\n\n'"
+        );
+    }
+
+    #[test]
+    fn quote_shell_test() {
+        // シェルで展開して、元のバイト列に戻ることを確かめる
+        for bytes in [
+            b"caf\xe9 'x'%\\".as_slice(),
+            b"\xff\n",
+            b"\n\xff\n\n",
+            b"\xff \t\n",
+        ] {
+            let word = quote(OsStr::from_bytes(bytes)).unwrap();
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {word}"))
+                .output()
+                .unwrap();
+            assert_eq!(output.stdout, bytes);
+        }
+    }
+
+    #[test]
+    fn errno_test() {
+        let out = |status, stderr: &str| CmdOutput {
+            status,
+            stdout: vec![],
+            stderr: stderr.as_bytes().to_vec(),
+        };
+        assert_eq!(out(127, "sh: 1: df: not found").errno(), libc::ENOSYS);
+        assert_eq!(
+            out(1, "ln: failed to access 'a': No such file or directory").errno(),
+            libc::ENOENT
+        );
+        assert_eq!(out(1, "mv: invalid option -- 'T'").errno(), libc::ENOSYS);
+        // GNU以外のddの、バイト単位の指定
+        assert_eq!(out(1, "dd: unknown operand iflag").errno(), libc::ENOSYS);
+        assert_eq!(
+            out(1, "dd: invalid argument 'count_bytes' to 'iflag'").errno(),
+            libc::ENOSYS
+        );
+        assert_eq!(out(1, "dd: Invalid argument").errno(), libc::EINVAL);
+        assert_eq!(out(1, "something strange").errno(), libc::EIO);
+        assert_eq!(out(0, "").into_result().unwrap(), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,194 @@
+//! リモートのinode番号からのinode番号生成モジュール
+//! リモートのファイルのデバイス番号とinode番号から、再マウントしても変わらないinode番号を作る。
+
+use super::{remote_cmd, Error};
+use ssh2::Session;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// ルートと異なるデバイス上のファイルに割り当てる範囲の先頭
+const OTHER_DEVICE_BASE: u64 = 1 << 62;
+/// リモートのinode番号が使えない場合に、連番で割り当てる範囲の先頭
+pub(super) const FALLBACK_INODE_BASE: u64 = 1 << 63;
+
+/// リモートのinode番号の取得と変換
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct RemoteIno {
+    /// マウントしたディレクトリのデバイス番号
+    root_dev: u64,
+}
+
+impl RemoteIno {
+    /// マウントしたディレクトリのデバイス番号を取得して生成する。
+    pub(super) fn fetch(session: &Session, root: &Path) -> Result<Self, Error> {
+        let (root_dev, _) = Self::stat(session, root)?;
+        Ok(Self { root_dev })
+    }
+
+    /// pathのファイルのinode番号を求める。(シンボリックリンクはたどらない)
+    pub(super) fn ino_of(&self, session: &Session, path: &Path) -> Result<u64, Error> {
+        let (dev, ino) = Self::stat(session, path)?;
+        Ok(self.make_ino(dev, ino))
+    }
+
+    /// ディレクトリ内の全エントリのinode番号を、一回のリモートコマンドで求める。
+    pub(super) fn inos_in_dir(
+        &self,
+        session: &Session,
+        dir: &Path,
+    ) -> Result<HashMap<OsString, u64>, Error> {
+        let command = format!(
+            "find {} -mindepth 1 -maxdepth 1 -printf '%D %i %f\\0'",
+            remote_cmd::quote(dir)?
+        );
+        let out = remote_cmd::exec(session, &command)?.into_result()?;
+        Ok(Self::parse_find(&out)
+            .into_iter()
+            .map(|(name, dev, ino)| (name, self.make_ino(dev, ino)))
+            .collect())
+    }
+
+    /// inode番号から、リモートのファイルを検索する。
+    /// 見つかれば、rootからのパスの各要素の名前と、そのinode番号を返す。
+    /// ルートと別のデバイスに割り当てたinode番号は、元に戻せないので検索できない。
+    pub(super) fn find_by_ino(
+        &self,
+        session: &Session,
+        root: &Path,
+        ino: u64,
+    ) -> Result<Option<Vec<(OsString, u64)>>, Error> {
+        if ino <= fuser::FUSE_ROOT_ID || ino >= OTHER_DEVICE_BASE {
+            return Ok(None);
+        }
+        let command = format!(
+            "find {} -xdev -inum {ino} -print0 -quit",
+            remote_cmd::quote(root)?
+        );
+        let out = remote_cmd::exec(session, &command)?.into_result()?;
+        let found = out.split(|b| *b == 0).next().unwrap_or_default();
+        let Ok(relative) = Path::new(OsStr::from_bytes(found)).strip_prefix(root) else {
+            return Ok(None);
+        };
+        let names = relative
+            .iter()
+            .map(|n| n.to_os_string())
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return Ok(None);
+        }
+        // 途中のディレクトリも登録できるよう、パスの各要素のinode番号を、まとめて取得する。
+        let mut paths = Vec::new();
+        let mut path = root.to_path_buf();
+        for name in &names {
+            path.push(name);
+            paths.push(remote_cmd::quote(&path)?);
+        }
+        let command = format!("stat -c '%d %i' -- {}", paths.join(" "));
+        let out = remote_cmd::exec(session, &command)?.into_result()?;
+        let inos = Self::parse_stat(&String::from_utf8_lossy(&out))
+            .into_iter()
+            .map(|(dev, ino)| self.make_ino(dev, ino))
+            .collect::<Vec<_>>();
+        if inos.len() != names.len() || inos.last() != Some(&ino) {
+            return Ok(None);
+        }
+        Ok(Some(names.into_iter().zip(inos).collect()))
+    }
+
+    /// "stat -c '%d %i'"で、デバイス番号とinode番号を取得する。
+    fn stat(session: &Session, path: &Path) -> Result<(u64, u64), Error> {
+        let command = format!("stat -c '%d %i' -- {}", remote_cmd::quote(path)?);
+        let out = remote_cmd::exec(session, &command)?.into_result()?;
+        Self::parse_stat(&String::from_utf8_lossy(&out))
+            .first()
+            .copied()
+            .ok_or(Error(libc::EIO))
+    }
+
+    /// "stat -c '%d %i'"の出力(1行に1ファイル)を解析する。解析できない行以降は無視する。
+    fn parse_stat(output: &str) -> Vec<(u64, u64)> {
+        output
+            .lines()
+            .map_while(|line| {
+                let mut fields = line.split_whitespace().map(|f| f.parse::<u64>().ok());
+                Some((fields.next()??, fields.next()??))
+            })
+            .collect()
+    }
+
+    /// "find -printf '%D %i %f\0'"の出力を解析する。解析できないエントリは無視する。
+    fn parse_find(output: &[u8]) -> Vec<(OsString, u64, u64)> {
+        output
+            .split(|b| *b == 0)
+            .filter_map(|entry| {
+                let mut fields = entry.splitn(3, |b| *b == b' ');
+                let mut num = || std::str::from_utf8(fields.next()?).ok()?.parse().ok();
+                let dev = num()?;
+                let ino = num()?;
+                let name = fields.next().filter(|n| !n.is_empty())?;
+                Some((OsStr::from_bytes(name).to_os_string(), dev, ino))
+            })
+            .collect()
+    }
+
+    /// デバイス番号とinode番号から、カーネルに返すinode番号を作る。
+    /// ルートと同じデバイスなら、リモートのinode番号をそのまま使う。
+    /// 別のデバイス(リモート側でのマウント)なら、両者を混ぜて別の範囲に割り当てる。
+    fn make_ino(&self, dev: u64, ino: u64) -> u64 {
+        if dev == self.root_dev && ino > fuser::FUSE_ROOT_ID && ino < OTHER_DEVICE_BASE {
+            return ino;
+        }
+        // splitmix64 (Rustのバージョンによって結果が変わらないよう、自前で計算する)
+        let mut h = dev.rotate_left(32) ^ ino;
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+        OTHER_DEVICE_BASE | (h & (OTHER_DEVICE_BASE - 1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_find_test() {
+        let out = b"2049 131 file.txt\x002049 140 with space\x0064768 2 mnt\x00broken\x00";
+        assert_eq!(
+            RemoteIno::parse_find(out),
+            vec![
+                ("file.txt".into(), 2049, 131),
+                ("with space".into(), 2049, 140),
+                ("mnt".into(), 64768, 2),
+            ]
+        );
+        assert_eq!(RemoteIno::parse_find(b""), vec![]);
+    }
+
+    #[test]
+    fn parse_stat_test() {
+        assert_eq!(
+            RemoteIno::parse_stat("2049 2\n2049 131\n"),
+            vec![(2049, 2), (2049, 131)]
+        );
+        assert_eq!(
+            RemoteIno::parse_stat("2049 2\nbad\n2049 3\n"),
+            vec![(2049, 2)]
+        );
+        assert_eq!(RemoteIno::parse_stat(""), vec![]);
+    }
+
+    #[test]
+    fn make_ino_test() {
+        let r = RemoteIno { root_dev: 2049 };
+        assert_eq!(r.make_ino(2049, 131), 131);
+        // ルートのinode番号と重なるものは、別の範囲へ
+        assert!(r.make_ino(2049, 1) >= OTHER_DEVICE_BASE);
+        let other = r.make_ino(64768, 131);
+        assert!((OTHER_DEVICE_BASE..FALLBACK_INODE_BASE).contains(&other));
+        assert_eq!(other, r.make_ino(64768, 131));
+        assert_ne!(other, r.make_ino(64769, 131));
+    }
+}
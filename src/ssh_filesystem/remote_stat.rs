@@ -0,0 +1,249 @@
+//! リモートのstatコマンドによる詳細なファイル属性取得モジュール
+//! SFTPv3の属性にない、ナノ秒単位の時刻、ctime、作成日時、リンク数、実際のブロック数を取得する。
+
+use super::{
+    remote_cmd::{self, CmdOutput},
+    Error,
+};
+use fuser::FileAttr;
+use ssh2::Session;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// statの出力形式。ファイル名に'|'が含まれてもよいよう、ファイル名を最後に置く。
+const FORMAT: &str = "%h|%b|%B|%o|%X|%x|%Y|%y|%Z|%z|%W|%w|%n\\0";
+
+/// リモートのstatで取得した属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct RemoteStat {
+    nlink: u32,
+    /// 512バイト単位のブロック数
+    blocks: u64,
+    blksize: u32,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    /// 作成日時(取得できない場合はNone)
+    crtime: Option<SystemTime>,
+}
+
+impl RemoteStat {
+    /// pathのファイルの属性を取得する。(シンボリックリンクはたどらない)
+    /// statコマンドが存在しない場合は、ENOSYSを返す。出力が解析できない場合は、EIO。
+    pub(super) fn fetch(session: &Session, path: &Path) -> Result<Self, Error> {
+        let command = format!("stat --printf '{FORMAT}' -- {}", remote_cmd::quote(path)?);
+        let out = Self::exec(session, &command)?;
+        Self::find(&out, path).ok_or(Error(libc::EIO))
+    }
+
+    /// ディレクトリ内の全エントリの属性を、一回のリモートコマンドで取得する。
+    pub(super) fn fetch_dir(
+        session: &Session,
+        dir: &Path,
+    ) -> Result<HashMap<OsString, Self>, Error> {
+        let command = format!(
+            "find {} -mindepth 1 -maxdepth 1 -exec stat --printf '{FORMAT}' {{}} +",
+            remote_cmd::quote(dir)?
+        );
+        let out = Self::exec(session, &command)?;
+        let stats = Self::parse(&out);
+        if stats.is_empty() && !out.is_empty() {
+            return Err(Error(libc::EIO));
+        }
+        Ok(stats
+            .into_iter()
+            .filter_map(|(path, s)| Some((path.file_name()?.to_os_string(), s)))
+            .collect())
+    }
+
+    /// リモートコマンドを実行し、標準出力を返す。
+    fn exec(session: &Session, command: &str) -> Result<Vec<u8>, Error> {
+        Self::result(remote_cmd::exec(session, command)?)
+    }
+
+    /// コマンドの結果を返す。
+    /// ENOSYSは、コマンドが存在しない(終了ステータス126,127)か、
+    /// "--printf"を解釈できない(GNU以外のstat)場合のみとし、
+    /// それ以外の失敗でENOSYSと推定されたものは、EIOとする。
+    fn result(out: CmdOutput) -> Result<Vec<u8>, Error> {
+        /// オプションを解釈できない場合のメッセージ
+        const USAGE_ERRORS: &[&str] = &[
+            "invalid option",
+            "unrecognized option",
+            "illegal option",
+            "usage:",
+        ];
+        match out.status {
+            0 => Ok(out.stdout),
+            126 | 127 => Err(Error(libc::ENOSYS)),
+            _ => {
+                let msg = String::from_utf8_lossy(&out.stderr);
+                if USAGE_ERRORS.iter().any(|m| msg.contains(m)) {
+                    return Err(Error(libc::ENOSYS));
+                }
+                match out.errno() {
+                    libc::ENOSYS => Err(Error(libc::EIO)),
+                    e => Err(Error(e)),
+                }
+            }
+        }
+    }
+
+    /// statの出力から、指定したパスの属性を探す。
+    fn find(output: &[u8], path: &Path) -> Option<Self> {
+        Self::parse(output)
+            .into_iter()
+            .find(|(p, _)| p == path)
+            .map(|(_, s)| s)
+    }
+
+    /// ファイル属性に、取得した値を反映する。
+    pub(super) fn apply(&self, attr: &mut FileAttr) {
+        attr.nlink = self.nlink;
+        attr.blocks = self.blocks;
+        attr.blksize = self.blksize;
+        attr.atime = self.atime;
+        attr.mtime = self.mtime;
+        attr.ctime = self.ctime;
+        if let Some(crtime) = self.crtime {
+            attr.crtime = crtime;
+        }
+    }
+
+    /// statの出力を解析し、(パス, 属性)のリストにする。解析できないものは無視する。
+    fn parse(output: &[u8]) -> Vec<(PathBuf, Self)> {
+        output
+            .split(|b| *b == 0)
+            .filter(|r| !r.is_empty())
+            .filter_map(Self::parse_record)
+            .collect()
+    }
+
+    fn parse_record(record: &[u8]) -> Option<(PathBuf, Self)> {
+        let mut fields = record.splitn(13, |b| *b == b'|');
+        let mut f = Vec::with_capacity(12);
+        for _ in 0..12 {
+            f.push(std::str::from_utf8(fields.next()?).ok()?);
+        }
+        let path = PathBuf::from(OsStr::from_bytes(fields.next()?));
+        let block_size = f[2].parse::<u64>().ok()?;
+        let stat = Self {
+            nlink: f[0].parse().ok()?,
+            blocks: f[1].parse::<u64>().ok()? * block_size / 512,
+            blksize: f[3].parse().ok()?,
+            atime: Self::parse_time(f[4], f[5])?,
+            mtime: Self::parse_time(f[6], f[7])?,
+            ctime: Self::parse_time(f[8], f[9])?,
+            crtime: match f[10] {
+                "0" | "-" => None,
+                _ => Self::parse_time(f[10], f[11]),
+            },
+        };
+        Some((path, stat))
+    }
+
+    /// 秒("%Y"等)と、人が読む形式("%y"等、"2024-01-02 03:04:05.123456789 +0900")から時刻を作る。
+    /// ナノ秒は、人が読む形式の小数部から取り出す。
+    fn parse_time(secs: &str, human: &str) -> Option<SystemTime> {
+        let secs = secs.parse::<i64>().ok()?;
+        let nanos = human
+            .split_whitespace()
+            .nth(1)
+            .and_then(|t| t.split_once('.'))
+            .map_or(Some(0), |(_, frac)| {
+                let digits = format!("{:0<9.9}", frac);
+                digits.parse::<u32>().ok()
+            })?;
+        let base = if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+        };
+        Some(base + Duration::from_nanos(nanos as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let out = b"2|8|512|4096|1700000000|2023-11-15 07:13:20.123456789 +0900|\
+1700000001|2023-11-15 07:13:21.5 +0900|1700000002|2023-11-15 07:13:22.000000001 +0900|\
+0|-|/home/mito/a|b.txt\x00\
+1|0|512|4096|-2|1969-12-31 23:59:58.250000000 +0000|0|1970-01-01 00:00:00 +0000|\
+0|1970-01-01 00:00:00.000000000 +0000|1600000000|2020-09-13 12:26:40.000000100 +0000|/x/y\x00";
+        let stats = RemoteStat::parse(out);
+        assert_eq!(stats.len(), 2);
+        let (path, s) = &stats[0];
+        assert_eq!(path, Path::new("/home/mito/a|b.txt"));
+        assert_eq!(s.nlink, 2);
+        assert_eq!(s.blocks, 8);
+        assert_eq!(s.blksize, 4096);
+        assert_eq!(
+            s.atime,
+            UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)
+        );
+        assert_eq!(
+            s.mtime,
+            UNIX_EPOCH + Duration::new(1_700_000_001, 500_000_000)
+        );
+        assert_eq!(s.ctime, UNIX_EPOCH + Duration::new(1_700_000_002, 1));
+        assert_eq!(s.crtime, None);
+        let (path, s) = &stats[1];
+        assert_eq!(path, Path::new("/x/y"));
+        assert_eq!(s.atime, UNIX_EPOCH - Duration::from_millis(1750));
+        assert_eq!(s.mtime, UNIX_EPOCH);
+        assert_eq!(
+            s.crtime,
+            Some(UNIX_EPOCH + Duration::new(1_600_000_000, 100))
+        );
+
+        assert_eq!(RemoteStat::parse(b"stat: unrecognized option\n"), vec![]);
+    }
+
+    #[test]
+    fn result_test() {
+        let out = |status, stderr: &str| CmdOutput {
+            status,
+            stdout: b"out".to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        };
+        let result = |status, stderr| RemoteStat::result(out(status, stderr)).map_err(|e| e.0);
+        assert_eq!(result(0, ""), Ok(b"out".to_vec()));
+        assert_eq!(result(127, "sh: stat: not found"), Err(libc::ENOSYS));
+        // BSD, macOSのstat
+        assert_eq!(
+            result(
+                1,
+                "stat: illegal option -- -\nusage: stat [-FLnq] [-f format | -l | -r | -s | -x] [-t timefmt] [file ...]"
+            ),
+            Err(libc::ENOSYS)
+        );
+        assert_eq!(
+            result(1, "stat: unrecognized option '--printf'"),
+            Err(libc::ENOSYS)
+        );
+        assert_eq!(
+            result(1, "stat: cannot statx '/a': No such file or directory"),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(
+            result(1, "stat: cannot statx '/a': Function not implemented"),
+            Err(libc::EIO)
+        );
+    }
+
+    #[test]
+    fn find_test() {
+        let out = b"3|0|512|4096|0|-|0|-|0|-|0|-|/\x00";
+        let s = RemoteStat::find(out, Path::new("/")).unwrap();
+        assert_eq!(s.nlink, 3);
+        assert_eq!(RemoteStat::find(out, Path::new("/a")), None);
+        assert_eq!(RemoteStat::find(b"garbage", Path::new("/")), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! リモートユーザー情報モジュール
+
+use super::{remote_cmd, Error};
+use ssh2::{FileStat, Session};
+
+/// 接続したリモート側ユーザーのuidと、所属するグループ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct RemoteUser {
+    pub(super) uid: u32,
+    pub(super) gid: u32,
+    pub(super) groups: Vec<u32>,
+}
+
+impl RemoteUser {
+    /// リモートで"id"コマンドを実行して取得する。
+    pub(super) fn fetch(session: &Session) -> Result<Self, Error> {
+        let out = remote_cmd::exec(session, "id -u && id -g && id -G")?.into_result()?;
+        Self::parse(&String::from_utf8_lossy(&out)).ok_or(Error(libc::EIO))
+    }
+
+    /// "id -u", "id -g", "id -G"の出力を解析する。
+    fn parse(output: &str) -> Option<Self> {
+        let mut lines = output.lines();
+        let uid = lines.next()?.trim().parse().ok()?;
+        let gid = lines.next()?.trim().parse().ok()?;
+        let groups = lines
+            .next()?
+            .split_whitespace()
+            .map(|g| g.parse().ok())
+            .collect::<Option<Vec<u32>>>()?;
+        Some(Self { uid, gid, groups })
+    }
+
+    /// ファイルの属性から、このユーザーがmaskで示されたアクセスを行えるかを判定する。
+    /// maskは、access(2)のR_OK, W_OK, X_OKの組み合わせ。(F_OKは常に可)
+    pub(super) fn can_access(&self, stat: &FileStat, mask: i32) -> bool {
+        let want = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+        let perm = stat.perm.unwrap_or(0);
+        if self.uid == 0 {
+            // rootは読み書き自由。実行は、いずれかの実行ビットがあるか、ディレクトリであること。
+            return want & libc::X_OK as u32 == 0 || perm & 0o111 != 0 || stat.is_dir();
+        }
+        let shift = if stat.uid == Some(self.uid) {
+            6
+        } else if stat
+            .gid
+            .is_some_and(|g| g == self.gid || self.groups.contains(&g))
+        {
+            3
+        } else {
+            0
+        };
+        (perm >> shift) & want == want
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stat(uid: u32, gid: u32, perm: u32) -> FileStat {
+        FileStat {
+            size: None,
+            uid: Some(uid),
+            gid: Some(gid),
+            perm: Some(perm),
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        let user = RemoteUser::parse("1000\n1000\n1000 4 27 100\n").unwrap();
+        assert_eq!(
+            user,
+            RemoteUser {
+                uid: 1000,
+                gid: 1000,
+                groups: vec![1000, 4, 27, 100]
+            }
+        );
+        assert_eq!(RemoteUser::parse("1000\n"), None);
+        assert_eq!(RemoteUser::parse("mito\n1000\n1000\n"), None);
+    }
+
+    #[test]
+    fn can_access_test() {
+        let user = RemoteUser::parse("1000\n1000\n1000 100\n").unwrap();
+        let (r, w, x) = (libc::R_OK, libc::W_OK, libc::X_OK);
+        // 所有者
+        assert!(user.can_access(&stat(1000, 1000, libc::S_IFREG | 0o644), r | w));
+        assert!(!user.can_access(&stat(1000, 1000, libc::S_IFREG | 0o644), x));
+        // グループ
+        assert!(user.can_access(&stat(0, 100, libc::S_IFREG | 0o664), w));
+        assert!(!user.can_access(&stat(0, 100, libc::S_IFREG | 0o644), w));
+        // その他
+        assert!(user.can_access(&stat(0, 0, libc::S_IFREG | 0o644), r));
+        assert!(!user.can_access(&stat(0, 0, libc::S_IFREG | 0o644), w));
+        assert!(user.can_access(&stat(0, 0, libc::S_IFREG | 0o600), libc::F_OK));
+        // 所有者のビットが優先される
+        assert!(!user.can_access(&stat(1000, 0, libc::S_IFREG | 0o077), r));
+
+        let root = RemoteUser::parse("0\n0\n0\n").unwrap();
+        assert!(root.can_access(&stat(1000, 1000, libc::S_IFREG | 0o600), r | w));
+        assert!(!root.can_access(&stat(1000, 1000, libc::S_IFREG | 0o600), x));
+        assert!(root.can_access(&stat(1000, 1000, libc::S_IFDIR | 0o700), x));
+    }
+}
@@ -0,0 +1,196 @@
+//! スパースファイル対応モジュール
+//! SFTPにはファイル内の穴(hole)を扱う手段がないので、リモートのperlでlseek(2)を、
+//! リモートの"fallocate"コマンドでfallocate(2)を実行する。
+
+use super::{remote_cmd, Error};
+use ssh2::{FileStat, Session};
+use std::path::Path;
+
+/// SEEK_DATA/SEEK_HOLEでのシークを行い、結果の位置を表示するperlスクリプト
+/// 引数は、パス、オフセット、穴を探すなら1。
+/// perlのFcntlはSEEK_DATA/SEEK_HOLEを定義していないので、OS毎の値を使う。(macOSのみ逆順)
+const SEEK_SCRIPT: &str = r#"my ($p, $o, $h) = @ARGV;
+my ($data, $hole) = $^O eq "darwin" ? (4, 3) : (3, 4);
+open(my $f, "<", $p) or die "$!\n";
+my $w = $h ? $hole : $data;
+my $r = sysseek($f, $o, $w);
+defined $r or die "$!\n";
+print $r + 0;"#;
+
+/// リモートのファイルで、offset以降の最初のデータ(hole=falseの時)または穴(hole=trueの時)の位置を求める。
+/// 見つからなければENXIO。リモートでperlが使えない場合はENOSYS、
+/// リモートのOSがSEEK_DATA/SEEK_HOLEに対応していない場合はEINVAL。
+pub(super) fn seek(session: &Session, path: &Path, offset: u64, hole: bool) -> Result<u64, Error> {
+    let command = format!(
+        "perl -e {} -- {} {offset} {}",
+        remote_cmd::quote(SEEK_SCRIPT)?,
+        remote_cmd::quote(path)?,
+        hole as u8
+    );
+    let out = remote_cmd::exec(session, &command)?.into_result()?;
+    String::from_utf8_lossy(&out)
+        .trim()
+        .parse()
+        .map_err(|_| Error(libc::EIO))
+}
+
+/// リモートの"fallocate"コマンドで、fallocate(2)を実行する。
+/// modeは、fallocate(2)のFALLOC_FL_*の組み合わせ。コマンドで扱えないmodeはEOPNOTSUPP。
+/// コマンドが無い、あるいは古くてオプションに対応していない場合はENOSYS。
+pub(super) fn fallocate(
+    session: &Session,
+    path: &Path,
+    offset: u64,
+    len: u64,
+    mode: i32,
+) -> Result<(), Error> {
+    let command = format!(
+        "fallocate {} -o {offset} -l {len} -- {}",
+        fallocate_options(mode)?,
+        remote_cmd::quote(path)?
+    );
+    remote_cmd::exec(session, &command)?.into_result()?;
+    Ok(())
+}
+
+/// fallocate(2)のmodeを、"fallocate"コマンドのオプションに変換する。
+fn fallocate_options(mode: i32) -> Result<&'static str, Error> {
+    use libc::{
+        FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_INSERT_RANGE, FALLOC_FL_KEEP_SIZE,
+        FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE,
+    };
+    const PUNCH_HOLE: i32 = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+    const ZERO_RANGE_KEEP_SIZE: i32 = FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE;
+    match mode {
+        0 => Ok(""),
+        FALLOC_FL_KEEP_SIZE => Ok("-n"),
+        PUNCH_HOLE => Ok("-p"),
+        FALLOC_FL_ZERO_RANGE => Ok("-z"),
+        ZERO_RANGE_KEEP_SIZE => Ok("-z -n"),
+        FALLOC_FL_COLLAPSE_RANGE => Ok("-c"),
+        FALLOC_FL_INSERT_RANGE => Ok("-i"),
+        _ => Err(Error(libc::EOPNOTSUPP)),
+    }
+}
+
+/// データが全て0かどうか。
+pub(super) fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+/// ファイル内に穴を開ける最小の長さ。
+/// これより短い範囲は、ブロックを解放できないので、リモートのコマンドを使わず、通常の書き込みとする。
+const HOLE_MIN: u64 = 4096;
+
+/// 全て0のデータの書き込みを、穴として行う方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Hole {
+    /// ファイル内で穴を開ける範囲(オフセット, 長さ)
+    pub(super) punch: Option<(u64, u64)>,
+    /// ファイル末尾を越える場合の、拡張後のサイズ
+    pub(super) extend: Option<u64>,
+}
+
+impl Hole {
+    /// サイズsizeのファイルへの、offsetからlenバイトの書き込みを、穴として行う方法を求める。
+    /// ファイル内の部分が短すぎる場合は、通常の書き込みとするのでNone。
+    pub(super) fn plan(size: u64, offset: u64, len: u64) -> Option<Self> {
+        let end = offset + len;
+        let punch = (offset < size).then(|| (offset, end.min(size) - offset));
+        if punch.is_some_and(|(_, len)| len < HOLE_MIN) {
+            return None;
+        }
+        Some(Self {
+            punch,
+            extend: (end > size).then_some(end),
+        })
+    }
+}
+
+/// ファイルハンドルの属性と、パスの属性が、同じファイルのものと見なせるか。
+/// SFTPv3の属性にはinode番号がないので、サイズ・更新日時・許可属性・所有者で比べる。
+pub(super) fn same_file(handle: &FileStat, path: &FileStat) -> bool {
+    handle.size == path.size
+        && handle.mtime == path.mtime
+        && handle.perm == path.perm
+        && handle.uid == path.uid
+        && handle.gid == path.gid
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(mode: i32) -> Result<&'static str, i32> {
+        fallocate_options(mode).map_err(|e| e.0)
+    }
+
+    #[test]
+    fn hole_plan_test() {
+        // ファイル内
+        assert_eq!(
+            Hole::plan(65536, 4096, 8192),
+            Some(Hole {
+                punch: Some((4096, 8192)),
+                extend: None
+            })
+        );
+        // ファイル末尾以降は、サイズの拡張のみ
+        assert_eq!(
+            Hole::plan(4096, 4096, 8192),
+            Some(Hole {
+                punch: None,
+                extend: Some(12288)
+            })
+        );
+        // 末尾をまたぐ
+        assert_eq!(
+            Hole::plan(8192, 0, 16384),
+            Some(Hole {
+                punch: Some((0, 8192)),
+                extend: Some(16384)
+            })
+        );
+        // ファイル内の部分が短いものは、通常の書き込み
+        assert_eq!(Hole::plan(65536, 0, 512), None);
+        assert_eq!(Hole::plan(1000, 0, 8192), None);
+    }
+
+    #[test]
+    fn same_file_test() {
+        let stat = |size, mtime| FileStat {
+            size: Some(size),
+            uid: Some(1000),
+            gid: Some(1000),
+            perm: Some(0o100644),
+            atime: Some(1),
+            mtime: Some(mtime),
+        };
+        assert!(same_file(&stat(10, 100), &stat(10, 100)));
+        let mut accessed = stat(10, 100);
+        accessed.atime = Some(2);
+        assert!(same_file(&stat(10, 100), &accessed));
+        assert!(!same_file(&stat(10, 100), &stat(11, 100)));
+        assert!(!same_file(&stat(10, 100), &stat(10, 101)));
+    }
+
+    #[test]
+    fn fallocate_options_test() {
+        assert_eq!(options(0), Ok(""));
+        assert_eq!(options(libc::FALLOC_FL_KEEP_SIZE), Ok("-n"));
+        assert_eq!(
+            options(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE),
+            Ok("-p")
+        );
+        assert_eq!(
+            options(libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE),
+            Ok("-z -n")
+        );
+        // PUNCH_HOLEは、KEEP_SIZEとの組み合わせのみ有効
+        assert_eq!(options(libc::FALLOC_FL_PUNCH_HOLE), Err(libc::EOPNOTSUPP));
+        assert_eq!(
+            options(libc::FALLOC_FL_UNSHARE_RANGE),
+            Err(libc::EOPNOTSUPP)
+        );
+    }
+}
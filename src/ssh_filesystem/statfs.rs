@@ -0,0 +1,124 @@
+//! ファイルシステム統計情報(statfs)取得モジュール
+
+use super::{remote_cmd, Error};
+use ssh2::{Session, Sftp};
+use std::path::Path;
+
+/// statfsの応答に必要な情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct StatFs {
+    pub(super) blocks: u64,
+    pub(super) bfree: u64,
+    pub(super) bavail: u64,
+    pub(super) files: u64,
+    pub(super) ffree: u64,
+    pub(super) bsize: u32,
+    pub(super) namelen: u32,
+    pub(super) frsize: u32,
+}
+
+impl StatFs {
+    /// fstatvfs@openssh.com拡張を使って取得する。
+    /// この拡張は、ファイルハンドルにしか使えない(OpenSSHはディレクトリハンドルを受け付けない)ので、
+    /// ディレクトリを、ファイルとして読み込み専用で開いて使う。
+    pub(super) fn from_sftp(sftp: &Sftp, path: &Path) -> Result<Self, Error> {
+        let mut file = sftp.open(path)?;
+        let vfs = file.statvfs()?;
+        Ok(Self {
+            blocks: vfs.f_blocks,
+            bfree: vfs.f_bfree,
+            bavail: vfs.f_bavail,
+            files: vfs.f_files,
+            ffree: vfs.f_ffree,
+            bsize: vfs.f_bsize as u32,
+            namelen: vfs.f_namemax as u32,
+            frsize: vfs.f_frsize as u32,
+        })
+    }
+
+    /// 拡張が使えないサーバー向けに、リモートの"df"コマンドの結果から取得する。
+    pub(super) fn from_df(session: &Session, path: &Path) -> Result<Self, Error> {
+        let path = remote_cmd::quote(path)?;
+        let command = format!("df -P -k -- {path} && {{ df -P -i -- {path} || true; }}");
+        let out = remote_cmd::exec(session, &command)?.into_result()?;
+        Self::parse_df(&String::from_utf8_lossy(&out)).ok_or(Error(libc::EIO))
+    }
+
+    /// "df -P -k"と"df -P -i"を続けて実行した出力を解析する。
+    /// inode数が取得できない場合は0とする。
+    fn parse_df(output: &str) -> Option<Self> {
+        let mut lines = output.lines().filter(|l| !l.starts_with("Filesystem"));
+        let (blocks, used, avail) = Self::parse_df_line(lines.next()?)?;
+        let (files, ffree) = lines
+            .next()
+            .and_then(Self::parse_df_line)
+            .map_or((0, 0), |(total, _, free)| (total, free));
+        Some(Self {
+            blocks,
+            bfree: blocks.saturating_sub(used),
+            bavail: avail,
+            files,
+            ffree,
+            bsize: 1024,
+            namelen: 255,
+            frsize: 1024,
+        })
+    }
+
+    /// dfの1行から、総数・使用数・空き数を取り出す。
+    /// ファイルシステム名やマウント先に空白が含まれてもよいよう、使用率の列を基準に数える。
+    fn parse_df_line(line: &str) -> Option<(u64, u64, u64)> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let cap = fields
+            .iter()
+            .enumerate()
+            .skip(3)
+            .find(|(_, f)| f.ends_with('%') || **f == "-")
+            .map(|(i, _)| i)?;
+        let num = |i: usize| fields[i].parse::<u64>().ok();
+        Some((num(cap - 3)?, num(cap - 2)?, num(cap - 1)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_df_test() {
+        let output = "\
+Filesystem     1024-blocks     Used Available Capacity Mounted on
+/dev/sda2        479151816 94321504 360422284      21% /
+Filesystem      Inodes  IUsed    IFree IUse% Mounted on
+/dev/sda2     30474240 993413 29480827    4% /
+";
+        let s = StatFs::parse_df(output).unwrap();
+        assert_eq!(s.blocks, 479151816);
+        assert_eq!(s.bfree, 479151816 - 94321504);
+        assert_eq!(s.bavail, 360422284);
+        assert_eq!(s.files, 30474240);
+        assert_eq!(s.ffree, 29480827);
+        assert_eq!(s.frsize, 1024);
+
+        // inode数の取れないファイルシステムと、空白を含む名前
+        let output = "\
+Filesystem     1024-blocks  Used Available Capacity Mounted on
+my volume            10000  2500      7500      25% /mnt/my volume
+Filesystem     Inodes IUsed IFree IUse% Mounted on
+my volume           0     0     0     - /mnt/my volume
+";
+        let s = StatFs::parse_df(output).unwrap();
+        assert_eq!((s.blocks, s.bfree, s.bavail), (10000, 7500, 7500));
+        assert_eq!((s.files, s.ffree), (0, 0));
+
+        // inode情報なし
+        let output = "\
+Filesystem     1024-blocks  Used Available Capacity Mounted on
+/dev/sdb1            10000  2500      7500      25% /data
+";
+        let s = StatFs::parse_df(output).unwrap();
+        assert_eq!((s.files, s.ffree), (0, 0));
+
+        assert_eq!(StatFs::parse_df(""), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! シンボリックリンクのリンク先変換モジュール
+//! --transform-symlinks時、マウントしたディレクトリ内を指す絶対パスのリンク先を、
+//! マウント上でも同じファイルを指すよう変換する。
+
+use std::path::{Component, Path, PathBuf};
+
+/// リモートのリンク先を、マウント上で見せるリンク先に変換する。
+/// top(マウントしたリモートのディレクトリ)以下を指す絶対パスは、リンクの位置からの相対パスにする。
+/// それ以外は、変換しないのでNone。
+pub(super) fn to_local(top: &Path, link: &Path, target: &Path) -> Option<PathBuf> {
+    if !target.is_absolute() || !is_plain(target) || !target.starts_with(top) {
+        return None;
+    }
+    Some(relative_path(link.parent()?, target))
+}
+
+/// 作成するリンクのリンク先が、ローカルのマウントポイント以下を指す絶対パスなら、
+/// マウントポイントからの相対的な部分を返す。(リモートの絶対パスにするため)
+/// それ以外は、変換しないのでNone。
+pub(super) fn strip_mount_point<'a>(mount_point: &Path, target: &'a Path) -> Option<&'a Path> {
+    if !target.is_absolute() || !is_plain(target) {
+        return None;
+    }
+    target.strip_prefix(mount_point).ok()
+}
+
+/// パスに、"."と".."が含まれていないかどうか。
+fn is_plain(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::RootDir | Component::Normal(_)))
+}
+
+/// ディレクトリfromから、toへの相対パスを求める。(どちらも、"."と".."を含まない絶対パス)
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let mut from = from.components().peekable();
+    let mut to = to.components().peekable();
+    while from.peek().is_some() && from.peek() == to.peek() {
+        from.next();
+        to.next();
+    }
+    let mut path = PathBuf::new();
+    for _ in from {
+        path.push("..");
+    }
+    path.extend(to);
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn relative_path_test() {
+        let rel = |from, to| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(
+            rel("/home/mito/a", "/home/mito/a/b.txt"),
+            Path::new("b.txt")
+        );
+        assert_eq!(
+            rel("/home/mito/a/b", "/home/mito/c/d"),
+            Path::new("../../c/d")
+        );
+        assert_eq!(rel("/home/mito/a", "/home/mito"), Path::new(".."));
+        assert_eq!(rel("/home/mito", "/home/mito"), Path::new("."));
+    }
+
+    #[test]
+    fn transform_test() {
+        let top = Path::new("/home/mito");
+        let link = Path::new("/home/mito/a/link");
+        assert_eq!(
+            to_local(top, link, Path::new("/home/mito/b/c")),
+            Some(PathBuf::from("../b/c"))
+        );
+        // マウントの外、相対パス、".."を含むものは変換しない
+        assert_eq!(to_local(top, link, Path::new("/etc/hosts")), None);
+        assert_eq!(to_local(top, link, Path::new("/home/mitox")), None);
+        assert_eq!(to_local(top, link, Path::new("../b")), None);
+        assert_eq!(to_local(top, link, Path::new("/home/mito/../x")), None);
+
+        let mount = Path::new("/mnt/remote");
+        assert_eq!(
+            strip_mount_point(mount, Path::new("/mnt/remote/b/c")),
+            Some(Path::new("b/c"))
+        );
+        assert_eq!(strip_mount_point(mount, Path::new("/etc/hosts")), None);
+        assert_eq!(strip_mount_point(mount, Path::new("b/c")), None);
+    }
+}
@@ -0,0 +1,279 @@
+//! 拡張属性(xattr)操作モジュール
+//! リモートの"getfattr","setfattr"コマンドを、execチャネル経由で実行する。
+//! 設定は、XATTR_CREATE/XATTR_REPLACEを原子的に扱えるよう、perlからlsetxattr(2)を呼ぶ。
+//! perl(またはsyscall.ph)が使えない場合は、"setfattr --restore"で設定する。
+
+use super::{
+    remote_cmd::{self, CmdOutput},
+    Error,
+};
+use ssh2::Session;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// 取得できなかった拡張属性の、inode毎のキャッシュ
+/// カーネルは、書き込みの度にsecurity.capabilityを問い合わせるので、
+/// 存在しない(ENODATA)、あるいは対応していない(ENOTSUP)という結果を覚えておき、
+/// 同じ問い合わせでリモートのコマンドを実行しないようにする。
+#[derive(Debug, Default)]
+pub(super) struct Missing {
+    names: HashMap<u64, HashMap<OsString, i32>>,
+}
+
+impl Missing {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// 覚えている取得失敗のエラー番号を返す。
+    pub(super) fn get(&self, ino: u64, name: &OsStr) -> Option<i32> {
+        self.names.get(&ino)?.get(name).copied()
+    }
+
+    /// 取得の失敗を覚える。ENODATA, ENOTSUP以外のエラーは、一時的なものかもしれないので覚えない。
+    pub(super) fn insert(&mut self, ino: u64, name: &OsStr, errno: i32) {
+        if errno == libc::ENODATA || errno == libc::ENOTSUP {
+            self.names
+                .entry(ino)
+                .or_default()
+                .insert(name.to_os_string(), errno);
+        }
+    }
+
+    /// 設定、削除した属性を忘れる。
+    pub(super) fn remove(&mut self, ino: u64, name: &OsStr) {
+        if let Some(names) = self.names.get_mut(&ino) {
+            names.remove(name);
+            if names.is_empty() {
+                self.names.remove(&ino);
+            }
+        }
+    }
+
+    /// inodeの全ての属性を忘れる。
+    pub(super) fn clear(&mut self, ino: u64) {
+        self.names.remove(&ino);
+    }
+}
+
+/// 拡張属性の値を取得する。
+pub(super) fn get(session: &Session, path: &Path, name: &OsStr) -> Result<Vec<u8>, Error> {
+    let command = format!(
+        "getfattr -h --only-values -n {} -- {}",
+        remote_cmd::quote(name)?,
+        remote_cmd::quote(path)?
+    );
+    run(session, &command)
+}
+
+/// 拡張属性の名前の一覧を、NUL区切りで取得する。
+pub(super) fn list(session: &Session, path: &Path) -> Result<Vec<u8>, Error> {
+    let command = format!(
+        "getfattr -h -m - --absolute-names -- {}",
+        remote_cmd::quote(path)?
+    );
+    run(session, &command).map(|out| parse_list(&out))
+}
+
+/// 拡張属性を設定する。値は、大きくてもよいよう、標準入力で渡す。
+/// XATTR_CREATE, XATTR_REPLACEは、lsetxattr(2)にそのまま渡すので、存在の確認と設定は原子的に行われる。
+pub(super) fn set(
+    session: &Session,
+    path: &Path,
+    name: &OsStr,
+    value: &[u8],
+    flags: i32,
+) -> Result<(), Error> {
+    /// 標準入力の値を、lsetxattr(2)で設定するperlスクリプト
+    /// システムコール番号(syscall.ph)が得られなければ、コマンドがない場合と同じく127で終了する。
+    const SET_SCRIPT: &str = r#"binmode STDIN; my $v = do { local $/; <STDIN> } // "";
+eval { require "syscall.ph" } or exit 127;
+syscall(&SYS_lsetxattr, $ARGV[0], $ARGV[1], $v, length($v), $ARGV[2] + 0) == 0 or die "$!\n";"#;
+    let command = format!(
+        "perl -e {} -- {} {} {}",
+        remote_cmd::quote(SET_SCRIPT)?,
+        remote_cmd::quote(path)?,
+        remote_cmd::quote(name)?,
+        flags & (libc::XATTR_CREATE | libc::XATTR_REPLACE)
+    );
+    let output = remote_cmd::exec_with_input(session, &command, value)?;
+    if output.status != 127 {
+        return result(output).map(|_| ());
+    }
+    let command = restore_command(path, name, flags)?;
+    let input = restore_input(path, name, value);
+    result(remote_cmd::exec_with_input(session, &command, &input)?).map(|_| ())
+}
+
+/// perlが使えない場合に、標準入力の内容を"setfattr --restore"で設定するコマンド。
+/// XATTR_CREATE, XATTR_REPLACEは、事前にgetfattrで存在を確認する。(原子的ではない)
+fn restore_command(path: &Path, name: &OsStr, flags: i32) -> Result<String, Error> {
+    let check = format!(
+        "getfattr -h -n {} -- {}",
+        remote_cmd::quote(name)?,
+        remote_cmd::quote(path)?
+    );
+    let restore = "setfattr -h --restore=-";
+    Ok(if flags & libc::XATTR_CREATE != 0 {
+        format!("if {check} >/dev/null 2>&1; then echo 'File exists' >&2; exit 1; fi; {restore}")
+    } else if flags & libc::XATTR_REPLACE != 0 {
+        format!("{check} >/dev/null && {restore}")
+    } else {
+        restore.to_string()
+    })
+}
+
+/// "setfattr --restore"に渡す、getfattrのダンプ形式の入力を作る。
+/// 値は16進("0x...")で表し、パスと名前の特殊文字は"\ooo"の8進表記にする。
+fn restore_input(path: &Path, name: &OsStr, value: &[u8]) -> Vec<u8> {
+    let mut input = b"# file: ".to_vec();
+    input.extend(escape(path.as_os_str().as_bytes()));
+    input.push(b'\n');
+    input.extend(escape(name.as_bytes()));
+    if value.is_empty() {
+        input.extend(b"=\"\"");
+    } else {
+        input.extend(b"=0x");
+        for b in value {
+            input.extend(format!("{b:02x}").as_bytes());
+        }
+    }
+    input.extend(b"\n\n");
+    input
+}
+
+/// getfattrのダンプ形式で、空白や'='等を含む名前を"\ooo"の8進表記にする。
+fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for b in bytes {
+        if b.is_ascii_graphic() && !matches!(b, b'\\' | b'=') {
+            escaped.push(*b);
+        } else {
+            escaped.extend(format!("\\{b:03o}").as_bytes());
+        }
+    }
+    escaped
+}
+
+/// 拡張属性を削除する。
+pub(super) fn remove(session: &Session, path: &Path, name: &OsStr) -> Result<(), Error> {
+    let command = format!(
+        "setfattr -h -x {} -- {}",
+        remote_cmd::quote(name)?,
+        remote_cmd::quote(path)?
+    );
+    run(session, &command).map(|_| ())
+}
+
+/// コマンドを実行する。
+fn run(session: &Session, command: &str) -> Result<Vec<u8>, Error> {
+    result(remote_cmd::exec(session, command)?)
+}
+
+/// コマンドの結果を返す。
+/// リモートにコマンドがない場合は、拡張属性非対応としてENOTSUPを返す。
+fn result(output: CmdOutput) -> Result<Vec<u8>, Error> {
+    match output.into_result() {
+        Err(Error(libc::ENOSYS)) => Err(Error(libc::ENOTSUP)),
+        r => r,
+    }
+}
+
+/// getfattrの名前一覧出力を、listxattr形式(NUL区切り)に変換する。
+/// 名前中の特殊文字は、"\ooo"の8進表記で出力されているので、元に戻す。
+fn parse_list(output: &[u8]) -> Vec<u8> {
+    let mut list = Vec::new();
+    for line in output.split(|c| *c == b'\n') {
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let mut i = 0;
+        while i < line.len() {
+            let octal = line
+                .get(i + 1..i + 4)
+                .filter(|_| line[i] == b'\\')
+                .and_then(|o| std::str::from_utf8(o).ok())
+                .and_then(|o| u8::from_str_radix(o, 8).ok());
+            match octal {
+                Some(c) => {
+                    list.push(c);
+                    i += 4;
+                }
+                None => {
+                    list.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+        list.push(0);
+    }
+    list
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_list_test() {
+        let output = b"# file: /home/mito/a.txt\nuser.comment\nsecurity.selinux\n\n";
+        assert_eq!(
+            parse_list(output),
+            b"user.comment\0security.selinux\0".to_vec()
+        );
+        let output = b"# file: /home/mito/a.txt\nuser.a\\040b\\134\n\n";
+        assert_eq!(parse_list(output), b"user.a b\\\0".to_vec());
+        assert_eq!(parse_list(b""), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn missing_test() {
+        let mut missing = Missing::new();
+        let cap = OsStr::new("security.capability");
+        missing.insert(2, cap, libc::ENODATA);
+        missing.insert(2, OsStr::new("user.a"), libc::EIO);
+        missing.insert(3, cap, libc::ENOTSUP);
+        assert_eq!(missing.get(2, cap), Some(libc::ENODATA));
+        assert_eq!(missing.get(2, OsStr::new("user.a")), None);
+        assert_eq!(missing.get(3, cap), Some(libc::ENOTSUP));
+        missing.remove(2, cap);
+        assert_eq!(missing.get(2, cap), None);
+        missing.clear(3);
+        assert_eq!(missing.get(3, cap), None);
+        assert!(missing.names.is_empty());
+    }
+
+    #[test]
+    fn restore_test() {
+        let path = Path::new(OsStr::from_bytes(b"/home/mito/a b=\xff"));
+        let name = OsStr::new("user.x\\y");
+        assert_eq!(
+            restore_input(path, name, b"\x00v"),
+            b"# file: /home/mito/a\\040b\\075\\377\nuser.x\\134y=0x0076\n\n".to_vec()
+        );
+        assert_eq!(
+            restore_input(Path::new("/a"), OsStr::new("user.e"), b""),
+            b"# file: /a\nuser.e=\"\"\n\n".to_vec()
+        );
+
+        let path = Path::new("/a");
+        let name = OsStr::new("user.e");
+        assert_eq!(
+            restore_command(path, name, 0).unwrap(),
+            "setfattr -h --restore=-"
+        );
+        assert_eq!(
+            restore_command(path, name, libc::XATTR_CREATE).unwrap(),
+            "if getfattr -h -n 'user.e' -- '/a' >/dev/null 2>&1; \
+             then echo 'File exists' >&2; exit 1; fi; setfattr -h --restore=-"
+        );
+        assert_eq!(
+            restore_command(path, name, libc::XATTR_REPLACE).unwrap(),
+            "getfattr -h -n 'user.e' -- '/a' >/dev/null && setfattr -h --restore=-"
+        );
+    }
+}